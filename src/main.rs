@@ -11,303 +11,259 @@ extern crate gfx_device_gl;
 extern crate uuid;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde;
+extern crate serde_json;
 extern crate toml;
 extern crate slotmap;
 extern crate wrapped2d;
+#[macro_use]
+extern crate clap;
 mod swingyships;
-use swingyships::objects::*;
-use swingyships::game::*;
-use swingyships::physics::FixRestitutionListener;
-use swingyships::level_loader::{LevelDef, ColliderProps, WeaponDef, load_level};
-
-use wrapped2d::b2;
-use wrapped2d::user_data::NoUserData;
-use wrapped2d::handle::TypedHandle;
-use std::env;
-
-use slotmap::SlotMap;
-
-use sprite::*;
-use ai_behavior::{
-    Action,
-    Sequence,
-    Wait,
-    WaitForever,
-    While,
-};
-
-use std::collections::HashMap;
-use std::io::prelude::*;
-use std::fs::{File, ReadDir, DirEntry};
-use std::rc::Rc;
-use std::cell::RefCell;
-use std::iter::FromIterator;
-use uuid::Uuid;
-
-use glium_graphics::{
-    Flip, Glium2d, GliumWindow, OpenGL, Texture, TextureSettings
-};
-use piston_window::{Event, PressEvent, MouseCursorEvent, MouseRelativeEvent, RenderEvent, AdvancedWindow};
-use piston::event_loop::EventLoop;
+use swingyships::game::{Game, InputMode};
+use swingyships::simulation::{Simulation, Input, FixedTimestep, DEFAULT_TICK_RATE, DEFAULT_MAX_CATCH_UP};
+use swingyships::level_loader::Sources;
+use swingyships::load_error::LoadError;
+use swingyships::replay::Replay;
+use swingyships::snapshot::Snapshot;
+
+use std::path::{Path, PathBuf};
+use std::process;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use glium_graphics::{Glium2d, GliumWindow, OpenGL};
+use piston_window::AdvancedWindow;
 use piston::window::WindowSettings;
 
-use glutin_window::GlutinWindow;
-
 fn main() {
-    let opengl = OpenGL::V3_0;
-    let (width, height) = (1000, 1000);
-    let ref mut window: GliumWindow =
-        WindowSettings::new("glium_graphics: image_test", [width, height])
-        .exit_on_esc(true).opengl(opengl).build().unwrap();
-    window.set_capture_cursor(true);
-    let mut g2d = Glium2d::new(opengl, window);
-
-    let mut game_objects: SlotMap<GameObjectKey, GameObject> = SlotMap::with_key();
-    let big_impacts = Rc::new(RefCell::new(Vec::new()));
-    let gravity = b2::Vec2 { x: 0., y: -10. };
-    let mut world = b2::World::<NoUserData>::new(&gravity);
-    world.set_contact_listener(Box::new(FixRestitutionListener{big_impacts: big_impacts.clone()}));
-    let mut scene: Scene<Texture> = Scene::new();
-
-    let assets = find_folder::Search::ParentsThenKids(3, 3)
-        .for_folder("assets").unwrap();
-
-    let tex = Rc::new(Texture::from_path(
-                    window,
-                    assets.join("images").join("rust.png"),
-                    Flip::None,
-                    &TextureSettings::new()
-    ).unwrap());
-
-    let chaser_tex = Rc::new(Texture::from_path(
-                    window,
-                    assets.join("images").join("rust_red.png"),
-                    Flip::None,
-                    &TextureSettings::new()
-    ).unwrap());
-
-    let player = make_player(&mut world, &mut scene, &tex, &mut game_objects);
-
-    let mut game = Game{
-        objects: game_objects,
-        world,
-        scene,
-        sprites: SlotMap::with_key(),
-        player: player,
-        cursor_captured: true
-    };
-
-
-
-
-/*
-    let bar_id;
-    let mut sprite = Sprite::from_texture(tex.clone());
-    bar_id = scene.add_child(sprite);
-*/
-
-    let mut walls_def = b2::BodyDef {
-        body_type: b2::BodyType::Static,
-        .. b2::BodyDef::new()
-    };
-
-    let walls_handle = game.world.create_body(&walls_def);
-    {
-        let mut body = game.world.body_mut(walls_handle);
-
-        let mut shape = b2::EdgeShape::new();
-        {
-            shape.set_v1(b2::Vec2{x:-2., y:2.});
-            shape.set_v2(b2::Vec2{x:-2., y:-102.});
-            let mut fixture_def = b2::FixtureDef::new();
-            fixture_def.restitution = 0.6;
-            let handle = body.create_fixture(&shape, &mut fixture_def);
-        }
+    let matches = app().get_matches();
+    match matches.subcommand() {
+        ("check", Some(args)) => {
+            let (level, props, weapons_dir) = level_paths(args);
+            process::exit(check(&level, &props, &weapons_dir));
+        },
+        ("simulate", Some(args)) => {
+            let ticks = value_t!(args, "ticks", u64).unwrap_or_else(|e| e.exit());
+            let mut sim = match args.value_of("snapshot") {
+                Some(path) => restore(Path::new(path)),
+                None => build(args).1,
+            };
+            for event in sim.run(ticks, &Input::default()) {
+                println!("{}", event);
+            }
+            print_state(&sim);
+        },
+        ("replay", Some(args)) => {
+            let replay = or_exit(Replay::load(Path::new(args.value_of("file").unwrap())));
+            if args.is_present("headless") {
+                let sim = or_exit(replay.run());
+                print_state(&sim);
+                match replay.matches(&sim) {
+                    Some(true) => println!("replay matches the recorded final state"),
+                    Some(false) => println!("replay DIVERGED from the recorded final state"),
+                    None => println!("replay has no recorded final state to compare against"),
+                }
+                return;
+            }
+            let sim = or_exit(replay.simulation());
+            play(args, sim, InputMode::Playback(replay), None);
+        },
+        (_, args) => {
+            // playing is the default when no subcommand is given
+            let args = args.unwrap_or(&matches);
+            let snapshot_path = args.value_of("snapshot");
+            let (sim, input_mode) = match snapshot_path {
+                Some(path) => (restore(Path::new(path)), InputMode::Live),
+                None => {
+                    let (sources, sim) = build(args);
+                    let input_mode = match args.value_of("record") {
+                        Some(_) => InputMode::Record(Replay::new(sources, sim.tick_rate, sim.seed)),
+                        None => InputMode::Live,
+                    };
+                    (sim, input_mode)
+                }
+            };
+            let game = play(args, sim, input_mode, snapshot_path.map(Path::new));
+
+            if let InputMode::Record(mut replay) = game.input_mode {
+                replay.finish(&game.sim);
+                let path = args.value_of("record").unwrap();
+                match replay.save(Path::new(path)) {
+                    Ok(()) => println!("recorded {} ticks to {}", replay.ticks, path),
+                    Err(e) => {
+                        println!("could not write replay file {}: {}", path, e);
+                        process::exit(1)
+                    }
+                }
+            }
+        },
+    }
+}
 
-        {
-            shape.set_v1(b2::Vec2{x:-2., y:-102.});
-            shape.set_v2(b2::Vec2{x:102., y:-102.});
-            let mut fixture_def = b2::FixtureDef::new();
-            fixture_def.restitution = 0.6;
-            let handle = body.create_fixture(&shape, &mut fixture_def);
-        }
+fn app() -> App<'static, 'static> {
+    let level_args = || vec![
+        Arg::with_name("level").long("level").value_name("FILE")
+            .help("Level to load [default: assets/level.toml]"),
+        Arg::with_name("props").long("props").value_name("FILE")
+            .help("Collider property sets [default: assets/weapon_classes.toml]"),
+        Arg::with_name("weapons-dir").long("weapons-dir").value_name("DIR")
+            .help("Directory of weapon definitions [default: assets/weapons]"),
+    ];
+    let sim_args = || vec![
+        Arg::with_name("tick-rate").long("tick-rate").value_name("HZ").validator(positive_tick_rate)
+            .help("Simulation ticks per second [default: 60]"),
+        Arg::with_name("seed").long("seed").value_name("N")
+            .help("Seed for everything random in the simulation [default: 0]"),
+    ];
+    let window_args = || vec![
+        Arg::with_name("width").long("width").value_name("PIXELS").default_value("1000")
+            .help("Window width"),
+        Arg::with_name("height").long("height").value_name("PIXELS").default_value("1000")
+            .help("Window height"),
+        Arg::with_name("vsync").long("vsync")
+            .help("Wait for vertical sync when drawing"),
+    ];
+    let play_args = || vec![
+        Arg::with_name("record").long("record").value_name("FILE")
+            .help("Record the session to a replay file"),
+        Arg::with_name("snapshot").long("snapshot").value_name("FILE")
+            .conflicts_with_all(&["record", "level", "props", "weapons-dir", "tick-rate", "seed"])
+            .help("Start from a snapshot; F5 saves to and F9 loads from this file"),
+    ];
+
+    App::new("swingyships")
+        .version(crate_version!())
+        .about("Swing weapons on chains at things that chase you")
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .args(&level_args()).args(&sim_args()).args(&window_args()).args(&play_args())
+        .subcommand(SubCommand::with_name("play")
+            .about("Play a level (the default)")
+            .args(&level_args()).args(&sim_args()).args(&window_args()).args(&play_args()))
+        .subcommand(SubCommand::with_name("check")
+            .about("Report every problem with a level and the weapons it could use")
+            .args(&level_args()))
+        .subcommand(SubCommand::with_name("simulate")
+            .about("Run a level without a window and print the final state")
+            .args(&level_args()).args(&sim_args())
+            .arg(Arg::with_name("ticks").long("ticks").value_name("N").required(true)
+                .help("Ticks to run"))
+            .arg(Arg::with_name("snapshot").long("snapshot").value_name("FILE")
+                .conflicts_with_all(&["level", "props", "weapons-dir", "tick-rate", "seed"])
+                .help("Start from a snapshot instead of a level")))
+        .subcommand(SubCommand::with_name("replay")
+            .about("Play back a recorded replay")
+            .args(&window_args())
+            .arg(Arg::with_name("file").value_name("FILE").required(true)
+                .help("Replay to play back"))
+            .arg(Arg::with_name("headless").long("headless")
+                .help("Run the replay without a window and check it against the recorded final state")))
+}
 
-        {
-            shape.set_v1(b2::Vec2{x:102., y:2.});
-            shape.set_v2(b2::Vec2{x:102., y:-102.});
-            let mut fixture_def = b2::FixtureDef::new();
-            fixture_def.restitution = 0.6;
-            let handle = body.create_fixture(&shape, &mut fixture_def);
+/// The assets folder next to or above the working directory, or quits if there isn't one.
+fn assets() -> PathBuf {
+    match find_folder::Search::ParentsThenKids(3, 3).for_folder("assets") {
+        Ok(path) => path,
+        Err(e) => {
+            println!("could not find an assets folder in or near the working directory: {}", e);
+            process::exit(1)
         }
+    }
+}
 
-        {
-            shape.set_v1(b2::Vec2{x:-2., y:2.});
-            shape.set_v2(b2::Vec2{x:102., y:2.});
-            let mut fixture_def = b2::FixtureDef::new();
-            fixture_def.restitution = 0.6;
-            let handle = body.create_fixture(&shape, &mut fixture_def);
-        }
+/// The level, props and weapons paths from the command line, falling back to the assets folder.
+fn level_paths(args: &ArgMatches) -> (PathBuf, PathBuf, PathBuf) {
+    let path = |name, default| args.value_of(name).map_or_else(|| assets().join(default), PathBuf::from);
+    (path("level", "level.toml"), path("props", "weapon_classes.toml"), path("weapons-dir", "weapons"))
+}
 
+fn positive_tick_rate(value: String) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(rate) if rate > 0. && rate.is_finite() => Ok(()),
+        Ok(_) => Err(String::from("the tick rate must be a positive number")),
+        Err(e) => Err(e.to_string()),
     }
+}
 
+/// Reads and builds the level given on the command line, quitting if anything is wrong with it.
+fn build(args: &ArgMatches) -> (Sources, Simulation) {
+    let tick_rate = match args.value_of("tick-rate") {
+        Some(_) => value_t!(args, "tick-rate", f64).unwrap_or_else(|e| e.exit()),
+        None => DEFAULT_TICK_RATE,
+    };
+    let seed = match args.value_of("seed") {
+        Some(_) => value_t!(args, "seed", u32).unwrap_or_else(|e| e.exit()),
+        None => 0,
+    };
+    let (level, props, weapons_dir) = level_paths(args);
+    let sources = or_exit(Sources::read(&level, &props, &weapons_dir));
+    let sim = or_exit(sources.build(tick_rate, seed));
+    (sources, sim)
+}
 
+/// Reads a snapshot and rebuilds its simulation, quitting if either fails.
+fn restore(path: &Path) -> Simulation {
+    or_exit(Snapshot::load(path).and_then(|snapshot| Simulation::restore(&snapshot.sim)))
+}
 
-/*
-    let bar_handle = world.create_body(&def);
-    {
-        let mut body = world.body_mut(bar_handle);
-        body.set_linear_damping(1.);
-        body.set_rotation_fixed(false);
+/// Opens a window and runs the game until it's closed.
+fn play(args: &ArgMatches, sim: Simulation, input_mode: InputMode, snapshot_path: Option<&Path>) -> Game {
+    let width = value_t!(args, "width", u32).unwrap_or_else(|e| e.exit());
+    let height = value_t!(args, "height", u32).unwrap_or_else(|e| e.exit());
 
-        let mut shape = b2::PolygonShape::new_box(15., 2.);
+    let opengl = OpenGL::V3_0;
+    let ref mut window: GliumWindow =
+        WindowSettings::new("swingyships", [width, height])
+        .exit_on_esc(true).vsync(args.is_present("vsync")).opengl(opengl).build().unwrap();
+    window.set_capture_cursor(true);
+    let mut g2d = Glium2d::new(opengl, window);
 
-        let handle = body.create_fast_fixture(&shape, 2.);
-        let mut fixture = body.fixture_mut(handle);
-        fixture.set_density(1.);
+    let timestep = FixedTimestep::new(sim.tick_rate, DEFAULT_MAX_CATCH_UP);
+    let mut game = Game::new(sim, timestep, input_mode, assets().join("images"));
+    if let Some(path) = snapshot_path {
+        game.snapshot_path = path.to_path_buf();
+        or_exit(Snapshot::load(path).and_then(|snapshot| game.restore(&snapshot, window)));
     }
-
-    let mut rev_joint_def = b2::RevoluteJointDef::new(ship_handle, bar_handle);
-    rev_joint_def.local_anchor_b = b2::Vec2{x:15., y:0.};
-    rev_joint_def.local_anchor_a = b2::Vec2{x:0., y:0.};
-    rev_joint_def.collide_connected = false;
-    rev_joint_def.enable_limit = false;
-    let rev_handle = world.create_joint(&rev_joint_def);
-*/
-
-    let (level_def, props_def, weapon_defs)  = read_files(&assets);
-    load_level(&mut game,
-        swingyships::level_loader::Textures{chaser: chaser_tex, default: tex.clone()},
-        level_def,
-        &weapon_defs,
-        &props_def);
-
     while let Some(e) = window.next() {
-        game.scene.event(&e);
-
-        for key in game.objects.keys() {
-            let handle = game.handle(key).unwrap();
-            game.obj_type(key).unwrap().update(&e, &game, handle);
-        }
-
-        if let Some(args) = e.render_args() {
-            game.world.step(1./60., 20, 20);
-
-            for big_impact in big_impacts.replace(Vec::new()) {
-                println!("big impact {:?}", big_impact);
-                let mut sprite = Sprite::from_texture(tex.clone());
-                sprite.set_opacity(0.0);
-                let sprite_id = game.scene.add_child(sprite);
-                let seq = Sequence(vec![
-                    Action(ScaleBy(0., draw_scale(0.2), draw_scale(0.2))),
-                    Action(Ease(EaseFunction::QuadraticOut, Box::new(FadeIn(0.5)))),
-                    Action(Ease(EaseFunction::QuadraticIn, Box::new(FadeOut(0.5))))
-                ]);
-                game.scene.run(sprite_id, &seq);
-                game.scene.run(sprite_id, &Action(FadeTo(0.0, 0.5)));
-                game.scene.child_mut(sprite_id).unwrap().set_position(big_impact.0.x as f64 * 10., -big_impact.0.y as f64 * 10.);
-                game.sprites.insert(SpriteObject{draw_id: sprite_id, kind: SpriteObjectType::Explosion(60)});
-            }
-
-            let mut to_destroy = Vec::new();
-            for (ref key, ref mut sprite) in &mut game.sprites {
-                if sprite.kind.update(&e) {
-                    to_destroy.push(*key);
-                }
-            }
-            if(to_destroy.len() > 0) {
-                println!("destroying {} sprites  of {} total", to_destroy.len(), game.sprites.len())
-            }
-            for key in to_destroy {
-                let obj = game.sprites.remove(key).unwrap();
-                game.scene.remove_child(obj.draw_id);
-            }
-
-            for key in game.objects.keys() {
-                let x = game.body(key).unwrap().position().x as f64 * 10.;
-                let y = -game.body(key).unwrap().position().y as f64 * 10.;
-                let angle = game.body(key).unwrap().angle() as f64;
-                let draw_id = game.draw_id(key).unwrap();
-                game.scene.child_mut(draw_id).unwrap().set_position(x, y);
-                game.scene.run(draw_id, &Action(RotateBy(0., angle)));
-            }
-
-            let mut target = window.draw();
-            g2d.draw(&mut target, args.viewport(), |c, g| {
-                graphics::clear([1.0, 1.0, 1.0, 1.0], g);
-                game.scene.draw(c.transform, g);
-            });
-            target.finish().unwrap();
-        }
-
-        if let Some(_) = e.press_args() {
-            game.cursor_captured = !game.cursor_captured;
-            window.set_capture_cursor(game.cursor_captured);
-        }
+        game.event(&e, window, &mut g2d);
     }
+    game
 }
 
-
-fn read_files(assets: &std::path::PathBuf) -> (LevelDef, HashMap<String, ColliderProps>, HashMap<String, WeaponDef>) {
-    let args: Vec<String> = env::args().collect();
-    let level_contents: String = match std::fs::read_to_string(&args[1]) {
-        Ok(s) => s,
-        Err(e) => {
-            println!("{}", e);
-            panic!("could not read contents of level file {}", args[1])
-        }
-    };
-    let level_def: LevelDef = match toml::from_str(&level_contents) {
-        Ok(def) => def,
-        Err(e) => {
-            println!("{}", e);
-            panic!("could not parse contents of level file")
-        }
-    };
-    let props_contents = match std::fs::read_to_string(&args[2]) {
-        Ok(s) => s,
-        Err(e) => {
-            println!("{}", e);
-            panic!("could not read contents of props file {}", args[2])
-        }
-    };
-    let props_def: HashMap<String, ColliderProps> = match toml::from_str(&props_contents) {
-        Ok(def) => def,
-        Err(e) => {
-            println!("{}", e);
-            panic!("could not parse contents of props file {}", args[2])
-        }
+/// Reports every problem with a level and the weapons it could use, returning the exit code.
+fn check(level: &Path, props: &Path, weapons_dir: &Path) -> i32 {
+    let errors = match Sources::read(level, props, weapons_dir).and_then(|sources| sources.parse()) {
+        Ok(defs) => defs.check(),
+        Err(errors) => errors,
     };
+    for e in &errors {
+        println!("{}", e);
+    }
+    if errors.is_empty() {
+        println!("no problems found");
+        0
+    } else {
+        println!("{} problem(s) found", errors.len());
+        1
+    }
+}
 
-    let mut weapon_defs: HashMap<String, WeaponDef> = HashMap::new();
-    for file in std::fs::read_dir(assets.join("weapons")).unwrap() {
-        let file = match file {
-            Ok(s) => s,
-            Err(e) => {
-              println!("{}", e);
-              panic!("could not read weapon file")
-            }
-        };
-        let weapon_contents = match std::fs::read_to_string(&file.path()) {
-            Ok(s) => s,
-            Err(e) => {
-              println!("{}", e);
-              panic!("could not read contents of weapon file {:?}", file.file_name())
-            }
-        };
-        let weapon_def: WeaponDef = match toml::from_str(&weapon_contents) {
-            Ok(def) => def,
-            Err(e) => {
+/// Unwraps a loading result, or reports every problem and quits.
+fn or_exit<T>(result: Result<T, Vec<LoadError>>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(errors) => {
+            for e in &errors {
                 println!("{}", e);
-                panic!("could not parse contents of weapon file {:?}", &file.file_name())
             }
-        };
-        weapon_defs.insert(weapon_def.name.clone(), weapon_def);
+            println!("{} problem(s) loading the level", errors.len());
+            process::exit(1)
+        }
     }
-
-    (level_def, props_def, weapon_defs)
 }
 
-// TODO: remove
-fn draw_scale(scale: f64) -> f64 {
-     -(1. - scale)
+fn print_state(sim: &Simulation) {
+    println!("player at {:?} with {:?} health after {} ticks, state {:016x}",
+        sim.body(sim.player).unwrap().position(), sim.objects[sim.player].health, sim.tick, sim.state_hash());
+    if sim.game_over {
+        println!("game over");
+    }
 }
@@ -0,0 +1,317 @@
+extern crate ai_behavior;
+extern crate piston_window;
+extern crate wrapped2d;
+extern crate slotmap;
+
+use swingyships::game::{GameObjectKey, GameObjectType};
+use swingyships::simulation::Simulation;
+
+use ai_behavior::{Action, ActionArgs, Behavior, Select, Sequence, State, Status, Wait, Failure, Running, Success, RUNNING};
+use ai_behavior::Behavior::{After, AlwaysSucceed, Fail, If, Pressed, Released, WaitForever, WhenAll, WhenAny, While};
+use piston_window::{Event, UpdateArgs};
+use wrapped2d::b2;
+use slotmap::KeyData;
+
+use std::f32;
+use std::fmt;
+
+/// Seconds between the lunges of a dashing chaser.
+pub const DASH_INTERVAL: f64 = 1.;
+/// Seconds a wandering object keeps going one way before it picks another.
+pub const WANDER_INTERVAL: f64 = 1.5;
+/// A swinging object yanks its weapon back once the tip is this much closer to the player
+/// than the object is.
+pub const CRACK_DISTANCE: f32 = 0.6;
+
+/// Something an object can do or check in its behavior tree. Checks and charges happen
+/// straight away; moving takes up the rest of the tick.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum AiAction {
+    /// Pushes toward the player.
+    Chase,
+    /// Circles the player `distance` away, counterclockwise unless `clockwise`.
+    Orbit {
+        distance: f32,
+        #[serde(default)]
+        clockwise: bool
+    },
+    /// Pushes away from the player.
+    Flee,
+    /// Pushes in a random direction that changes every `WANDER_INTERVAL` seconds.
+    Wander,
+    /// Lunges at the player with this many seconds of chasing at once.
+    Charge(f32),
+    /// Brings the tip of the object's weapon into the player: drags the tip at the player so
+    /// it swings round in an arc, then yanks back once the tip is out ahead to crack it in.
+    /// `aggression` from 0 to 1 swings harder. Fails if the object has no weapon.
+    Swing {
+        aggression: f32
+    },
+    /// Succeeds if the player is within this distance, fails otherwise.
+    PlayerWithin(f32),
+    /// Succeeds if the object has less than this much health left, fails otherwise.
+    HealthBelow(f32),
+}
+
+/// How a chaser goes after the player when its archetype doesn't give it a behavior tree,
+/// picked with `behavior = "dash"` and so on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChaserBehavior {
+    /// Pushes toward the player with a steady force.
+    #[default]
+    Chase,
+    /// Saves up its force and lunges at the player once every `DASH_INTERVAL` seconds.
+    Dash,
+    /// Swings its weapon at the player, or chases when it has none.
+    Swing,
+}
+
+/// A behavior tree read from a level file, like
+/// `ai = { Select = [{ Sequence = [{ Action = { HealthBelow = 5.0 } }, { Action = "Flee" }] }, { Action = "Chase" }] }`.
+/// Pauses use ai_behavior's own `{ Wait = seconds }`.
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct AiBehavior(pub Behavior<AiAction>);
+
+/// An object's behavior tree and how far it has got through it. A tree that finishes starts
+/// over on the next tick.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Brain {
+    behavior: Behavior<AiAction>,
+    state: State<AiAction, ()>,
+    /// How hard the object pushes when it moves.
+    force: f32
+}
+
+impl ChaserBehavior {
+    pub fn tree(self, aggression: f32) -> Behavior<AiAction> {
+        match self {
+            ChaserBehavior::Chase => Action(AiAction::Chase),
+            ChaserBehavior::Dash => Sequence(vec![Action(AiAction::Charge(DASH_INTERVAL as f32)), Wait(DASH_INTERVAL)]),
+            ChaserBehavior::Swing => Select(vec![Action(AiAction::Swing{aggression}), Action(AiAction::Chase)]),
+        }
+    }
+}
+
+impl AiBehavior {
+    /// What's wrong with the tree: lists that ai_behavior can't start because they're empty,
+    /// waits for key presses that chasers never get, and times and distances that aren't
+    /// positive.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        tree_problems(&self.0, &mut problems);
+        problems
+    }
+}
+
+fn tree_problems(behavior: &Behavior<AiAction>, problems: &mut Vec<String>) {
+    fn children(name: &str, list: &[Behavior<AiAction>], problems: &mut Vec<String>) {
+        if list.is_empty() {
+            problems.push(format!("has an empty {}", name));
+        }
+        for child in list {
+            tree_problems(child, problems);
+        }
+    }
+    match *behavior {
+        Pressed(_) | Released(_) => problems.push(String::from("waits for a key, which chasers never press")),
+        Wait(seconds) if seconds.is_nan() || seconds < 0. => problems.push(format!("waits for {} seconds", seconds)),
+        Action(AiAction::Orbit{distance, ..}) if distance.is_nan() || distance <= 0. => problems.push(format!("orbits at a distance of {}", distance)),
+        Action(AiAction::Charge(seconds)) if seconds.is_nan() || seconds <= 0. => problems.push(format!("charges for {} seconds", seconds)),
+        Action(AiAction::Swing{aggression}) if !(0. ..= 1.).contains(&aggression) =>
+            problems.push(format!("swings with an aggression of {}, not between 0 and 1", aggression)),
+        Action(_) | Wait(_) | WaitForever => {},
+        Fail(ref child) | AlwaysSucceed(ref child) => tree_problems(child, problems),
+        If(ref condition, ref success, ref failure) => {
+            tree_problems(condition, problems);
+            tree_problems(success, problems);
+            tree_problems(failure, problems);
+        },
+        Select(ref list) => children("Select", list, problems),
+        Sequence(ref list) => children("Sequence", list, problems),
+        While(ref condition, ref list) => {
+            tree_problems(condition, problems);
+            children("While", list, problems);
+        },
+        WhenAll(ref list) => children("WhenAll", list, problems),
+        WhenAny(ref list) => children("WhenAny", list, problems),
+        After(ref list) => children("After", list, problems),
+    }
+}
+
+impl fmt::Debug for AiBehavior {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // ai_behavior's trees don't implement Debug
+        write!(f, "AiBehavior")
+    }
+}
+
+impl Brain {
+    pub fn new(behavior: Behavior<AiAction>, force: f32) -> Brain {
+        Brain{state: State::new(behavior.clone()), behavior, force}
+    }
+
+    /// Runs the tree for one tick of `sim` on behalf of `key`.
+    pub fn think(&mut self, sim: &Simulation, key: GameObjectKey) {
+        let event = Event::from(UpdateArgs{dt: 1. / sim.tick_rate});
+        let force = self.force;
+        let (status, _) = self.state.event(&event, &mut |args: ActionArgs<Event, AiAction, ()>|
+            act(sim, key, force, *args.action, args.dt));
+        if status != Running {
+            self.state = State::new(self.behavior.clone());
+        }
+    }
+}
+
+fn act(sim: &Simulation, key: GameObjectKey, force: f32, action: AiAction, dt: f64) -> (Status, f64) {
+    let (object, player) = match (sim.objects.get(key), sim.body(sim.player)) {
+        (Some(object), Some(player)) => (object, *player.position()),
+        _ => return (Failure, dt),
+    };
+    let tip = match action {
+        AiAction::Swing{..} => weapon_tip(sim, key),
+        _ => None,
+    };
+    let mut body = sim.world.body_mut(object.physics_handle);
+    let to_player = player - *body.position();
+    let distance = to_player.norm();
+    let toward = if distance > 0. { to_player / distance } else { b2::Vec2{x: 0., y: 0.} };
+
+    let push = match action {
+        AiAction::PlayerWithin(range) => return (if distance <= range { Success } else { Failure }, dt),
+        AiAction::HealthBelow(health) =>
+            return (if object.health.is_some_and(|h| h < health) { Success } else { Failure }, dt),
+        AiAction::Charge(seconds) => {
+            let center = *body.world_center();
+            body.apply_linear_impulse(&(toward * force * seconds), &center, true);
+            return (Success, dt);
+        },
+        AiAction::Swing{aggression} => match tip {
+            None => return (Failure, dt),
+            Some(_) if dt <= 0. => return RUNNING,
+            Some(tip) => {
+                let (direction, strength) = swing(*body.position(), tip, toward, distance, aggression);
+                body.apply_force_to_center(&(direction * force * strength), true);
+                return (Success, 0.);
+            },
+        },
+        // an earlier action already moved this tick
+        _ if dt <= 0. => return RUNNING,
+        AiAction::Chase => toward,
+        AiAction::Flee => -toward,
+        AiAction::Orbit{distance: radius, clockwise} => orbit(toward, distance, radius, clockwise),
+        AiAction::Wander => {
+            let turn = sim.tick / (WANDER_INTERVAL * sim.tick_rate).round().max(1.) as u64;
+            let angle = random(sim.seed, key, turn) * 2. * f32::consts::PI;
+            b2::Vec2{x: angle.cos(), y: angle.sin()}
+        },
+    };
+    body.apply_force_to_center(&(push * force), true);
+    (Success, 0.)
+}
+
+/// Direction to push in to circle the player `radius` away, or nowhere when right on top
+/// of the player.
+fn orbit(toward: b2::Vec2, distance: f32, radius: f32, clockwise: bool) -> b2::Vec2 {
+    if distance <= f32::EPSILON {
+        return b2::Vec2{x: 0., y: 0.};
+    }
+    let along = if clockwise { b2::Vec2{x: toward.y, y: -toward.x} } else { b2::Vec2{x: -toward.y, y: toward.x} };
+    // steer back onto the circle when drifting off it
+    let correction = ((distance - radius) / radius).clamp(-1., 1.);
+    let direction = along + toward * correction;
+    direction / direction.norm()
+}
+
+/// Position of the weapon collider farthest from `key`, which is the one doing the hitting.
+fn weapon_tip(sim: &Simulation, key: GameObjectKey) -> Option<b2::Vec2> {
+    let root = *sim.body(key)?.position();
+    sim.objects.iter()
+        // chain links are part of the weapon too, but never its tip
+        .filter(|&(_, object)| object.weapon.is_some_and(|part| part.root == key)
+            && matches!(object.obj_type, GameObjectType::Default))
+        .map(|(_, object)| *sim.world.body(object.physics_handle).position())
+        .map(|position| (position, (position - root).norm()))
+        .fold(None, |farthest: Option<(b2::Vec2, f32)>, (position, reach)| match farthest {
+            Some((_, most)) if most >= reach => farthest,
+            _ => Some((position, reach)),
+        })
+        .map(|(position, _)| position)
+}
+
+/// Direction and relative strength of the push that brings a weapon's tip into the player.
+fn swing(root: b2::Vec2, tip: b2::Vec2, toward: b2::Vec2, distance: f32, aggression: f32) -> (b2::Vec2, f32) {
+    let strength = 0.5 + aggression;
+    let tip_to_player = toward * distance - (tip - root);
+    if tip_to_player.norm() < distance * CRACK_DISTANCE {
+        // the tip has swung out ahead, so yank back to crack it into the player
+        (-toward, strength)
+    } else {
+        // drag the tip at the player; it lags behind and comes round in an arc
+        (tip_to_player / tip_to_player.norm(), strength)
+    }
+}
+
+/// A number from 0 to 1 that only depends on its arguments, so replays wander the same way.
+fn random(seed: u32, key: GameObjectKey, n: u64) -> f32 {
+    // splitmix64
+    let mut x = (seed as u64) ^ KeyData::from(key).as_ffi().rotate_left(17) ^ n.rotate_left(41);
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^= x >> 31;
+    (x >> 40) as f32 / (1u64 << 24) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate toml;
+
+    use swingyships::game::GameObjectType;
+    use swingyships::testing::build_level;
+    use super::{AiBehavior, orbit, weapon_tip};
+    use wrapped2d::b2;
+
+    #[derive(Deserialize)]
+    struct Tree {
+        ai: AiBehavior
+    }
+
+    fn problems(tree: &str) -> Vec<String> {
+        toml::from_str::<Tree>(&format!("ai = {}", tree)).expect("could not parse the tree").ai.problems()
+    }
+
+    #[test]
+    fn orbit_on_top_of_the_player_goes_nowhere() {
+        let zero = b2::Vec2{x: 0., y: 0.};
+        assert_eq!(orbit(zero, 0., 20., false), zero);
+        let away = orbit(b2::Vec2{x: 1., y: 0.}, 5., 20., false);
+        assert!((away.norm() - 1.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn weapon_tip_is_a_collider_and_not_a_chain_link() {
+        let sim = build_level("level.toml");
+        let (head, chaser) = sim.objects.iter()
+            .filter(|&(_, object)| matches!(object.obj_type, GameObjectType::Default))
+            .filter_map(|(key, object)| Some((key, object.weapon?.root)))
+            .find(|&(_, root)| matches!(sim.objects[root].obj_type, GameObjectType::Chaser))
+            .unwrap();
+        // pull the head in next to the chaser, leaving its chain links farther out
+        let position = *sim.body(chaser).unwrap().position() + b2::Vec2{x: 1., y: 0.};
+        let handle = sim.handle(head).unwrap();
+        sim.world.body_mut(handle).set_transform(&position, 0.);
+        assert_eq!(weapon_tip(&sim, chaser), Some(position));
+    }
+
+    #[test]
+    fn trees_that_cant_run_are_reported() {
+        assert!(problems(r#"{ Select = [{ Action = "Chase" }, { Wait = 1.0 }] }"#).is_empty());
+        assert_eq!(problems(r#"{ Sequence = [] }"#), vec!["has an empty Sequence"]);
+        assert_eq!(problems(r#"{ Select = [{ Action = { Orbit = { distance = 0.0 } } }, { Pressed = { Keyboard = "Space" } }] }"#),
+            vec!["orbits at a distance of 0", "waits for a key, which chasers never press"]);
+        assert_eq!(problems(r#"{ Fail = { Action = { Swing = { aggression = 1.5 } } } }"#),
+            vec!["swings with an aggression of 1.5, not between 0 and 1"]);
+    }
+}
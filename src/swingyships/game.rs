@@ -2,62 +2,132 @@ extern crate wrapped2d;
 extern crate ai_behavior;
 extern crate sprite;
 extern crate glium_graphics;
+extern crate graphics;
 extern crate uuid;
 extern crate piston_window;
 extern crate slotmap;
-use std::cell::Ref;
 
-use glium_graphics::Texture;
-use piston_window::{Event, PressEvent, MouseCursorEvent, MouseRelativeEvent, RenderEvent, AdvancedWindow};
+use glium_graphics::{Flip, Glium2d, GliumWindow, Texture, TextureSettings};
+use piston_window::{Event, Button, Key, PressEvent, MouseRelativeEvent, RenderEvent, AdvancedWindow};
 
 use wrapped2d::b2;
-use wrapped2d::user_data::NoUserData;
 use wrapped2d::handle::TypedHandle;
 
+use swingyships::simulation::{Simulation, Input, FixedTimestep, DEFAULT_MAX_CATCH_UP};
+use swingyships::objects::DEFAULT_TEXTURE;
+use swingyships::physics::{CollisionEvent, GameUserData};
+use swingyships::replay::Replay;
+use swingyships::snapshot::{Snapshot, SpriteSnapshot, body_id};
+use swingyships::load_error::LoadError;
+
 use sprite::*;
 use ai_behavior::{
     Action,
     Sequence,
-    Wait,
-    WaitForever,
-    While,
 };
 
-use slotmap::SlotMap;
+use slotmap::{SlotMap, SecondaryMap};
 
 use std::collections::HashMap;
+use std::mem;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::time::Instant;
 use uuid::Uuid;
 
+pub const SCENERY_COLOR: [f32; 4] = [0.3, 0.3, 0.3, 1.0];
+
+/// Impacts harder than this get an explosion.
+pub const BIG_IMPACT_IMPULSE: f32 = 500.;
+
 slotmap::new_key_type!(
     pub struct GameObjectKey;
     pub struct SpriteKey;
 );
 
+/// The Glium front end: draws a `Simulation` and feeds it player input.
 pub struct Game {
+    pub sim: Simulation,
     pub scene: Scene<Texture>,
-    pub world: b2::World<NoUserData>,
-    pub objects: SlotMap<GameObjectKey, GameObject>,
     pub sprites: SlotMap<SpriteKey, SpriteObject>,
-    pub player: GameObjectKey,
-    pub cursor_captured: bool,
+    pub draw_ids: SecondaryMap<GameObjectKey, Uuid>,
+    pub timestep: FixedTimestep,
+    pub input_mode: InputMode,
+    /// Where F5 saves a snapshot and F9 loads it from.
+    pub snapshot_path: PathBuf,
+    input: Input,
+    last_frame: Option<Instant>,
+    textures: HashMap<TextureId, Rc<Texture>>,
+    images: PathBuf,
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Where the input for each tick comes from.
+pub enum InputMode {
+    Live,
+    /// Live input, also written into the replay.
+    Record(Replay),
+    /// Input read back from the replay; live input is ignored.
+    Playback(Replay),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum GameObjectType {
     Default,
+    /// Moved by its `Brain` rather than by `update`.
     Chaser,
-    Player,
+    Player {
+        /// Strongest force the mouse can push the ship with.
+        max_force: f32
+    },
+    /// One link of a chain hanging `to` off `from`.
+    ChainLink {
+        from: GameObjectKey,
+        to: GameObjectKey,
+        /// Reaction force above which the joints holding the link snap. Unbreakable if `None`.
+        break_force: Option<f32>
+    },
+    /// A weapon lying in the arena, the level's `index`th pickup.
+    Pickup {
+        index: usize
+    },
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Index into `Simulation::textures`, which holds file names in the images folder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TextureId(pub usize);
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Appearance {
+    pub texture: TextureId,
+    pub scale: f64
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct GameObject {
+    #[serde(with = "body_id")]
     pub physics_handle: TypedHandle<b2::Body>,
-    pub draw_id: Uuid,
-    pub obj_type: GameObjectType
+    pub appearance: Appearance,
+    pub obj_type: GameObjectType,
+    /// Hit points left; objects without health can't be damaged.
+    pub health: Option<f32>,
+    /// Multiplier on the damage this object does to whatever it hits.
+    pub damage: f32,
+    /// The weapon this object is a collider or chain link of, if any.
+    pub weapon: Option<WeaponPart>
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Identifies one weapon instance, which can be made of many objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct WeaponId(pub u32);
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct WeaponPart {
+    pub weapon: WeaponId,
+    /// The object the weapon is attached to. Despawning it despawns the weapon too.
+    pub root: GameObjectKey
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum SpriteObjectType {
     Explosion(i32)
 }
@@ -69,38 +139,294 @@ pub struct SpriteObject {
 }
 
 impl Game {
-    pub fn body(&self, key: GameObjectKey) -> Option<Ref<b2::MetaBody<NoUserData>>> {
-        Some(self.world.body(self.objects.get(key)?.physics_handle))
+    pub fn new(sim: Simulation, timestep: FixedTimestep, input_mode: InputMode, images: PathBuf) -> Game {
+        Game {
+            sim,
+            scene: Scene::new(),
+            sprites: SlotMap::with_key(),
+            draw_ids: SecondaryMap::new(),
+            timestep,
+            input_mode,
+            snapshot_path: PathBuf::from("snapshot.json"),
+            input: Input::default(),
+            last_frame: None,
+            textures: HashMap::new(),
+            images,
+        }
     }
 
-    pub fn handle(&self, key: GameObjectKey) -> Option<TypedHandle<b2::Body>> {
-        Some(self.objects.get(key)?.physics_handle)
+    fn remove_sprite(&mut self, key: GameObjectKey) {
+        if let Some(draw_id) = self.draw_ids.remove(key) {
+            self.scene.remove_child(draw_id);
+        }
     }
 
     pub fn draw_id(&self, key: GameObjectKey) -> Option<Uuid> {
-        Some(self.objects.get(key)?.draw_id)
-    }
-
-    pub fn obj_type(&self, key: GameObjectKey) -> Option<GameObjectType> {
-        Some(self.objects.get(key)?.obj_type)
+        self.draw_ids.get(key).cloned()
     }
 
     pub fn sprite_type(&self, key: SpriteKey) -> Option<SpriteObjectType> {
         Some(self.sprites.get(key)?.kind)
     }
+
+    pub fn event(&mut self, e: &Event, window: &mut GliumWindow, g2d: &mut Glium2d) {
+        self.scene.event(e);
+
+        if let Some(args) = e.mouse_relative_args() {
+            self.input.mouse_relative[0] += args[0];
+            self.input.mouse_relative[1] += args[1];
+        }
+
+        match e.press_args() {
+            Some(Button::Keyboard(Key::F5)) => {
+                if self.sim.can_snapshot() {
+                    match self.snapshot().save(&self.snapshot_path) {
+                        Ok(()) => println!("saved snapshot to {:?}", self.snapshot_path),
+                        Err(e) => println!("could not write snapshot file {:?}: {}", self.snapshot_path, e),
+                    }
+                } else {
+                    println!("can't save a snapshot of a game with pulley joints");
+                }
+            },
+            Some(Button::Keyboard(Key::F9)) => {
+                if let InputMode::Live = self.input_mode {
+                    match Snapshot::load(&self.snapshot_path).and_then(|snapshot| self.restore(&snapshot, window)) {
+                        Ok(()) => println!("loaded snapshot from {:?}", self.snapshot_path),
+                        Err(errors) => {
+                            for e in errors {
+                                println!("{}", e);
+                            }
+                            println!("could not load snapshot from {:?}", self.snapshot_path);
+                        }
+                    }
+                } else {
+                    println!("can't load a snapshot while recording or playing back a replay");
+                }
+            },
+            Some(Button::Keyboard(Key::Q)) => {
+                self.input.cycle_weapon = true;
+            },
+            Some(Button::Keyboard(Key::G)) => {
+                self.input.drop_weapon = true;
+            },
+            Some(Button::Keyboard(Key::E)) => {
+                self.input.pick_up_weapon = true;
+            },
+            Some(_) => {
+                self.input.toggle_capture = !self.input.toggle_capture;
+            },
+            None => {}
+        }
+
+        if let Some(args) = e.render_args() {
+            let now = Instant::now();
+            let elapsed = match self.last_frame {
+                Some(last) => (now - last).as_secs_f64(),
+                None => 0.,
+            };
+            self.last_frame = Some(now);
+
+            let ticks = self.timestep.advance(elapsed);
+            if ticks > 0 {
+                let input = mem::take(&mut self.input);
+                for i in 0 .. ticks {
+                    self.tick(window, input.split(i, ticks));
+                }
+            }
+            self.sync_objects(window, self.timestep.alpha());
+
+            let mut target = window.draw();
+            let scene = &self.scene;
+            let world = &self.sim.world;
+            g2d.draw(&mut target, args.viewport(), |c, g| {
+                graphics::clear([1.0, 1.0, 1.0, 1.0], g);
+                draw_scenery(world, c.transform, g);
+                scene.draw(c.transform, g);
+            });
+            target.finish().unwrap();
+        }
+    }
+
+    /// Steps the simulation once with `input` from the player, or with the replay's input when
+    /// playing one back.
+    fn tick(&mut self, window: &mut GliumWindow, input: Input) {
+        let input = match self.input_mode {
+            InputMode::Live => input,
+            InputMode::Record(ref mut replay) => {
+                replay.record(self.sim.tick, &input);
+                input
+            },
+            InputMode::Playback(ref replay) => replay.input(self.sim.tick),
+        };
+        self.sim.step(&input);
+        if input.toggle_capture {
+            window.set_capture_cursor(self.sim.cursor_captured);
+        }
+        for event in self.sim.game_events() {
+            println!("{}", event);
+        }
+
+        self.spawn_explosions(window);
+        self.update_sprites();
+    }
+
+    fn texture(&mut self, window: &mut GliumWindow, id: TextureId) -> Rc<Texture> {
+        if let Some(tex) = self.textures.get(&id) {
+            return tex.clone();
+        }
+        let load = |window: &mut GliumWindow, name: &str|
+            Texture::from_path(window, self.images.join(name), Flip::None, &TextureSettings::new());
+        // texture names come from level files, so a typo shouldn't take the game down
+        let tex = Rc::new(match load(window, &self.sim.textures[id.0]) {
+            Ok(tex) => tex,
+            Err(e) => {
+                println!("could not load texture {:?}, drawing {} instead: {}", self.sim.textures[id.0], DEFAULT_TEXTURE, e);
+                load(window, DEFAULT_TEXTURE).unwrap()
+            }
+        });
+        self.textures.insert(id, tex.clone());
+        tex
+    }
+
+    fn spawn_explosions(&mut self, window: &mut GliumWindow) {
+        let big_impacts: Vec<(b2::Vec2, f32)> = self.sim.events().iter()
+            .filter_map(|event| match *event {
+                CollisionEvent::Impact{point, impulse, ..} if impulse > BIG_IMPACT_IMPULSE => Some((point, impulse)),
+                _ => None,
+            })
+            .collect();
+        for big_impact in big_impacts {
+            println!("big impact {:?}", big_impact);
+            let position = [big_impact.0.x as f64 * 10., -big_impact.0.y as f64 * 10.];
+            let kind = SpriteObjectType::Explosion(self.sim.tick_rate as i32);
+            self.spawn_sprite(window, kind, position);
+        }
+
+        let snapped_chains: Vec<b2::Vec2> = self.sim.events().iter()
+            .filter_map(|event| match *event {
+                CollisionEvent::ChainBroke{point, ..} => Some(point),
+                _ => None,
+            })
+            .collect();
+        for point in snapped_chains {
+            println!("chain snapped {:?}", point);
+            let position = [point.x as f64 * 10., -point.y as f64 * 10.];
+            let kind = SpriteObjectType::Explosion(self.sim.tick_rate as i32);
+            self.spawn_sprite(window, kind, position);
+        }
+    }
+
+    fn spawn_sprite(&mut self, window: &mut GliumWindow, kind: SpriteObjectType, position: [f64; 2]) {
+        match kind {
+            SpriteObjectType::Explosion(_) => {
+                let explosion_tex = self.sim.texture_id(DEFAULT_TEXTURE);
+                let mut sprite = Sprite::from_texture(self.texture(window, explosion_tex));
+                sprite.set_opacity(0.0);
+                let sprite_id = self.scene.add_child(sprite);
+                let seq = Sequence(vec![
+                    Action(ScaleBy(0., draw_scale(0.2), draw_scale(0.2))),
+                    Action(Ease(EaseFunction::QuadraticOut, Box::new(FadeIn(0.5)))),
+                    Action(Ease(EaseFunction::QuadraticIn, Box::new(FadeOut(0.5))))
+                ]);
+                self.scene.run(sprite_id, &seq);
+                self.scene.run(sprite_id, &Action(FadeTo(0.0, 0.5)));
+                self.scene.child_mut(sprite_id).unwrap().set_position(position[0], position[1]);
+                self.sprites.insert(SpriteObject{draw_id: sprite_id, kind});
+            }
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let sprites = self.sprites.values()
+            .map(|sprite| {
+                let (x, y) = self.scene.child(sprite.draw_id).unwrap().get_position();
+                SpriteSnapshot{kind: sprite.kind, position: [x, y]}
+            })
+            .collect();
+        Snapshot::new(self.sim.snapshot(), sprites)
+    }
+
+    /// Replaces the running game with a snapshot. Effects restart their animations. The game
+    /// keeps running as it was if the snapshot's level files no longer parse.
+    pub fn restore(&mut self, snapshot: &Snapshot, window: &mut GliumWindow) -> Result<(), Vec<LoadError>> {
+        let sim = Simulation::restore(&snapshot.sim)?;
+        for (_, draw_id) in self.draw_ids.drain() {
+            self.scene.remove_child(draw_id);
+        }
+        for (_, sprite) in self.sprites.drain() {
+            self.scene.remove_child(sprite.draw_id);
+        }
+        self.textures.clear();
+
+        // the snapshot may have been taken at a different tick rate
+        self.timestep = FixedTimestep::new(sim.tick_rate, DEFAULT_MAX_CATCH_UP);
+        self.sim = sim;
+        for sprite in &snapshot.sprites {
+            self.spawn_sprite(window, sprite.kind, sprite.position);
+        }
+        window.set_capture_cursor(self.sim.cursor_captured);
+        Ok(())
+    }
+
+    fn update_sprites(&mut self) {
+        let mut to_destroy = Vec::new();
+        for (key, sprite) in &mut self.sprites {
+            if sprite.kind.update() {
+                to_destroy.push(key);
+            }
+        }
+        if to_destroy.len() > 0 {
+            println!("destroying {} sprites  of {} total", to_destroy.len(), self.sprites.len())
+        }
+        for key in to_destroy {
+            let obj = self.sprites.remove(key).unwrap();
+            self.scene.remove_child(obj.draw_id);
+        }
+    }
+
+    /// Creates sprites for objects that don't have one yet, drops the sprites of removed objects
+    /// and moves every sprite to its body, `alpha` of the way from the previous tick to the current one.
+    fn sync_objects(&mut self, window: &mut GliumWindow, alpha: f64) {
+        let removed: Vec<GameObjectKey> = self.draw_ids.keys()
+            .filter(|&key| !self.sim.objects.contains_key(key))
+            .collect();
+        for key in removed {
+            self.remove_sprite(key);
+        }
+
+        let keys: Vec<GameObjectKey> = self.sim.objects.keys().collect();
+        for key in keys {
+            let draw_id = match self.draw_id(key) {
+                Some(id) => id,
+                None => {
+                    let appearance = self.sim.objects[key].appearance;
+                    let sprite = Sprite::from_texture(self.texture(window, appearance.texture));
+                    let id = self.scene.add_child(sprite);
+                    self.scene.run(id, &Action(ScaleBy(0., draw_scale(appearance.scale), draw_scale(appearance.scale))));
+                    self.draw_ids.insert(key, id);
+                    id
+                }
+            };
+
+            let (position, angle) = self.sim.interpolated(key, alpha).unwrap();
+            self.scene.child_mut(draw_id).unwrap().set_position(position.x as f64 * 10., -position.y as f64 * 10.);
+            self.scene.run(draw_id, &Action(RotateBy(0., angle as f64)));
+        }
+    }
 }
 
 impl GameObject {
     pub fn new(physics_handle: TypedHandle<b2::Body>,
-            draw_id: Uuid,
+            appearance: Appearance,
             obj_type: GameObjectType)
             -> GameObject {
-        GameObject{physics_handle, draw_id, obj_type}
+        GameObject{physics_handle, appearance, obj_type, health: None, damage: 1., weapon: None}
     }
 }
 
 impl SpriteObjectType {
-    pub fn update(&mut self, e: &Event) -> bool {
+    /// Advances the sprite by one tick, returning true once it should be removed.
+    pub fn update(&mut self) -> bool {
         let mut destroy = false;
         match self {
             SpriteObjectType::Explosion(ref mut i) => {
@@ -113,35 +439,73 @@ impl SpriteObjectType {
         destroy
     }
 }
+
 impl GameObjectType {
-    pub fn update(&self, e: &Event, game: &Game, handle: TypedHandle<b2::Body>) {
+    pub fn update(&self, sim: &Simulation, input: &Input, handle: TypedHandle<b2::Body>) {
         match self {
             &GameObjectType::Default => {},
-            &GameObjectType::Player => {
-                if game.cursor_captured {
-                    if let Some(args) = e.mouse_relative_args() {
-                        let mut force = b2::Vec2{x:args[0] as f32 * 10000., y:-args[1] as f32 * 10000.};
-
-                        let magnitude = (force.x * force.x + force.y * force.y).sqrt();
-                        if (magnitude > 2000.) {
-                            force.x = force.x * (2000. / magnitude);
-                            force.y = force.y * (2000. / magnitude);
-                        }
-                        let mut body = game.world.body_mut(handle);
-                        body.apply_force_to_center(&force, true);
+            &GameObjectType::Player{max_force} => {
+                let [dx, dy] = input.mouse_relative;
+                if sim.cursor_captured && !sim.game_over && (dx != 0. || dy != 0.) {
+                    let mut force = b2::Vec2{x:dx as f32 * 10000., y:-dy as f32 * 10000.};
+
+                    let magnitude = (force.x * force.x + force.y * force.y).sqrt();
+                    if magnitude > max_force {
+                        force.x = force.x * (max_force / magnitude);
+                        force.y = force.y * (max_force / magnitude);
                     }
+                    let mut body = sim.world.body_mut(handle);
+                    body.apply_force_to_center(&force, true);
                 }
             },
-            &GameObjectType::Chaser => {
-                if let Some(_) = e.render_args() {
-                    let mut chaser_body = game.world.body_mut(handle);
-                    let ship_body = game.body(game.player).unwrap();
-
-                    let vec = ship_body.position() - chaser_body.position();
-                    let vec = vec / vec.norm() * 2000.;
-                    chaser_body.apply_force_to_center(&vec, true);
-                }
+            &GameObjectType::Chaser => {},
+            &GameObjectType::ChainLink{..} => {},
+            &GameObjectType::Pickup{..} => {}
+        }
+    }
+}
+
+/// Draws the fixtures of every static body, which don't have sprites.
+fn draw_scenery<G: graphics::Graphics>(world: &b2::World<GameUserData>, transform: graphics::math::Matrix2d, g: &mut G) {
+    let to_screen = |p: b2::Vec2| [p.x as f64 * 10., -p.y as f64 * 10.];
+    for (_, body) in world.bodies() {
+        let body = body.borrow();
+        if body.body_type() != b2::BodyType::Static {
+            continue;
+        }
+        for (_, fixture) in body.fixtures() {
+            let fixture = fixture.borrow();
+            let shape = fixture.shape();
+            match *shape {
+                b2::UnknownShape::Edge(ref edge) => {
+                    let (a, b) = (to_screen(body.world_point(&edge.v1())), to_screen(body.world_point(&edge.v2())));
+                    graphics::line(SCENERY_COLOR, 1., [a[0], a[1], b[0], b[1]], transform, g);
+                },
+                b2::UnknownShape::Chain(ref chain) => {
+                    // loops repeat their first vertex at the end
+                    for pair in chain.vertices().windows(2) {
+                        let (a, b) = (to_screen(body.world_point(&pair[0])), to_screen(body.world_point(&pair[1])));
+                        graphics::line(SCENERY_COLOR, 1., [a[0], a[1], b[0], b[1]], transform, g);
+                    }
+                },
+                b2::UnknownShape::Polygon(ref polygon) => {
+                    let points: Vec<[f64; 2]> = (0 .. polygon.vertex_count())
+                        .map(|i| to_screen(body.world_point(polygon.vertex(i))))
+                        .collect();
+                    graphics::polygon(SCENERY_COLOR, &points, transform, g);
+                },
+                b2::UnknownShape::Circle(ref circle) => {
+                    let center = to_screen(body.world_point(&circle.position()));
+                    let radius = circle.radius() as f64 * 10.;
+                    graphics::ellipse(SCENERY_COLOR, [center[0] - radius, center[1] - radius, radius * 2., radius * 2.], transform, g);
+                },
+                b2::UnknownShape::Unknown => {}
             }
         }
     }
 }
+
+// TODO: remove
+fn draw_scale(scale: f64) -> f64 {
+     -(1. - scale)
+}
@@ -1,19 +1,236 @@
-use swingyships::objects::{make_chaser, make_ball, make_chain};
-use swingyships::game::{Game, GameObjectKey};
-use glium_graphics::Texture;
+extern crate toml;
+extern crate wrapped2d;
+
+use swingyships::objects::{make_arena, make_player, make_chaser, make_ball, make_chain, make_joint, make_pickup, DEFAULT_TEXTURE, CHASER_TEXTURE, PICKUP_TEXTURE, SHIP_RADIUS, PICKUP_RADIUS};
+use swingyships::game::{GameObjectKey, WeaponId, WeaponPart};
+use swingyships::ai::{AiBehavior, ChaserBehavior};
+use swingyships::simulation::{Loadout, Simulation};
+use swingyships::load_error::{LoadError, Location, Reference};
+use swingyships::physics::{CollisionFilters, CollisionLayer};
+
+use wrapped2d::b2;
+
+use serde::{Deserialize, Deserializer};
 
-use std::rc::Rc;
 use std::collections::HashMap;
+use std::f32;
+use std::fs;
+use std::ops::Deref;
+use std::path::Path;
+
+/// The text of a level, props or weapon file, kept so a game can be rebuilt exactly.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SourceFile {
+    pub path: String,
+    pub contents: String
+}
+
+/// Every file a level is built from.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Sources {
+    pub level: SourceFile,
+    pub props: SourceFile,
+    pub weapons: Vec<SourceFile>
+}
+
+/// Everything parsed out of a set of `Sources`.
+pub struct Definitions {
+    pub level: LevelDef,
+    pub props: HashMap<String, ColliderProps>,
+    pub weapons: HashMap<String, WeaponDef>,
+    /// Weapons with the same name as one in `weapons` from an earlier file.
+    pub duplicate_weapons: Vec<WeaponDef>
+}
+
+/// A value along with where it was written in its TOML file, so errors can point at it.
+/// Only works when deserializing TOML.
+#[derive(Clone, Debug)]
+pub struct Located<T> {
+    pub value: T,
+    pub span: (usize, usize)
+}
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct LevelDef {
+    #[serde(default)]
+    pub arena: ArenaDef,
+    #[serde(default)]
+    pub player: PlayerDef,
     pub chasers: Chasers,
-    pub weapons: Vec<WeaponInstance>
+    #[serde(default)]
+    pub weapons: Vec<WeaponInstance>,
+    /// Named places that waves spawn chasers at.
+    #[serde(default)]
+    pub spawn_points: HashMap<String, [f32; 2]>,
+    /// Chasers that arrive after the level starts, in order.
+    #[serde(default)]
+    pub waves: Vec<WaveDef>,
+    /// Weapons lying in the arena for the player to pick up.
+    #[serde(default)]
+    pub pickups: Vec<PickupDef>,
+    #[serde(default)]
+    pub collision: CollisionDef,
+    #[serde(default)]
+    pub damage: DamageDef,
+    #[serde(skip)]
+    pub file: SourceFile
+}
+
+/// Which collision layers hit each other, like
+/// `masks.enemy_weapon = ["player", "player_weapon", "wall"]` to let chasers' weapons pass
+/// through other chasers.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct CollisionDef {
+    /// Whether weapons hit ships on their own side.
+    pub friendly_fire: bool,
+    /// Whether a ship's weapons hit the ship itself, even without friendly fire.
+    pub hit_owner: bool,
+    pub masks: CollisionMasks
+}
+
+/// What can hurt ships besides other ships and their weapons. Neither does by default.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DamageDef {
+    /// Damage multiplier of the walls and obstacles.
+    pub scenery: f32,
+    /// Whether a ship's own weapons damage it when they hit it.
+    pub own_weapons: bool
+}
+
+/// The layers each layer hits, where layers left out hit everything. Two layers only hit
+/// each other if both of them list the other.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CollisionMasks {
+    pub player: Option<Vec<CollisionLayer>>,
+    pub player_weapon: Option<Vec<CollisionLayer>>,
+    pub enemy: Option<Vec<CollisionLayer>>,
+    pub enemy_weapon: Option<Vec<CollisionLayer>>,
+    pub wall: Option<Vec<CollisionLayer>>,
+    pub debris: Option<Vec<CollisionLayer>>
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WaveDef {
+    pub trigger: WaveTrigger,
+    pub spawns: Vec<WaveSpawnDef>
+}
+
+/// What starts a wave, written like `trigger = { kind = "timer", delay = 10.0 }`. A wave's
+/// trigger is only watched once the wave before it has spawned.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum WaveTrigger {
+    /// `delay` seconds after the previous wave spawned, or after the level started.
+    Timer {
+        delay: f64
+    },
+    /// `delay` seconds after the last chaser died.
+    Cleared {
+        #[serde(default)]
+        delay: f64
+    },
+    /// As soon as the player comes within `radius` of the point.
+    Area {
+        x: f32,
+        y: f32,
+        radius: f32
+    },
 }
 
-pub struct Textures {
-    pub chaser: Rc<Texture>,
-    pub default: Rc<Texture>
+#[derive(Clone, Debug, Deserialize)]
+pub struct WaveSpawnDef {
+    /// Name of the spawn point.
+    pub at: Located<String>,
+    #[serde(default)]
+    pub archetype: Option<Located<String>>,
+    /// Chasers are spread out in a ring around the spawn point when there's more than one.
+    #[serde(default = "default_spawn_count")]
+    pub count: u32,
+    /// Weapon class every chaser in this spawn carries.
+    #[serde(default)]
+    pub weapon: Option<Located<String>>
+}
+
+/// The walls around the level and the static obstacles inside them.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ArenaDef {
+    /// Lower left corner of the area inside the walls.
+    #[serde(default = "default_arena_min")]
+    pub min: [f32; 2],
+    #[serde(default = "default_arena_size")]
+    pub size: [f32; 2],
+    #[serde(default = "default_wall_restitution")]
+    pub restitution: f32,
+    #[serde(default = "default_wall_friction")]
+    pub friction: f32,
+    #[serde(default)]
+    pub obstacles: Vec<ObstacleDef>
+}
+
+/// The ship the mouse steers. Anything left out keeps the original ship's value.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct PlayerDef {
+    pub spawn: [f32; 2],
+    /// Sizes both the sprite and the body, like a chaser's scale.
+    pub scale: f64,
+    pub density: f32,
+    pub linear_damping: f32,
+    pub restitution: f32,
+    pub health: f32,
+    /// File name in the images folder.
+    pub texture: String,
+    /// Strongest force the mouse can push the ship with.
+    pub max_force: f32,
+    /// Weapon classes the player can cycle through, starting with the first one in hand.
+    pub loadout: Vec<Located<String>>
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ObstacleDef {
+    #[serde(flatten)]
+    pub shape: ShapeDef,
+    /// Overrides the arena's restitution.
+    pub restitution: Option<f32>,
+    /// Overrides the arena's friction.
+    pub friction: Option<f32>
+}
+
+/// A shape in world coordinates, picked with `shape = "box"` and so on.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "shape", rename_all = "lowercase")]
+pub enum ShapeDef {
+    Edge {
+        from: [f32; 2],
+        to: [f32; 2]
+    },
+    Box {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+        /// In radians.
+        #[serde(default)]
+        angle: f32
+    },
+    /// A convex polygon of 3 to 8 points, with no three in a line.
+    Polygon {
+        points: Vec<[f32; 2]>
+    },
+    Circle {
+        x: f32,
+        y: f32,
+        radius: f32
+    },
+    /// A line through every point, joined back to the first if `closed`.
+    Chain {
+        points: Vec<[f32; 2]>,
+        #[serde(default)]
+        closed: bool
+    },
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -22,20 +239,38 @@ pub struct Chasers {
     pub defs: Vec<ChaserDef>,
     #[serde(default)]
     pub named_defs: Vec<NamedChaserDef>,
-    pub props: ChaserProps
+    /// Used by every chaser that doesn't name an archetype.
+    pub props: ChaserProps,
+    /// Kinds of chaser, like "grunt" or "tank", that spawns can pick by name.
+    #[serde(default)]
+    pub archetypes: HashMap<String, ChaserProps>
 }
 
 // TODO: this is a hack, should I use something other than TOML?
 #[derive(Clone, Debug, Deserialize)]
 pub struct NamedChaserDef {
     pub def: ChaserDef,
-    pub name: String
+    pub name: Located<String>
+}
+
+/// A weapon lying at `x`, `y` that the player picks up by touching it, which puts it in hand
+/// and in the loadout.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PickupDef {
+    pub class: Located<String>,
+    pub x: f32,
+    pub y: f32,
+    /// File name in the images folder of the icon it's drawn as.
+    #[serde(default = "default_pickup_texture")]
+    pub texture: String
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct ChaserDef {
     pub x: f32,
     pub y: f32,
+    #[serde(default)]
+    pub archetype: Option<Located<String>>
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -43,20 +278,48 @@ pub struct ChaserProps {
     pub linear_damping: f32,
     pub scale: f64,
     pub density: f32,
-    pub restitution: f32
+    pub restitution: f32,
+    #[serde(default = "default_chaser_health")]
+    pub health: f32,
+    /// Multiplier on the damage this chaser does to whatever it hits.
+    #[serde(default = "default_damage")]
+    pub damage: f32,
+    /// File name in the images folder.
+    #[serde(default = "default_chaser_texture")]
+    pub texture: String,
+    #[serde(default = "default_chase_force")]
+    pub chase_force: f32,
+    #[serde(default)]
+    pub behavior: ChaserBehavior,
+    /// From 0 to 1, how hard `behavior = "swing"` goes after the player.
+    #[serde(default = "default_aggression")]
+    pub aggression: f32,
+    /// A behavior tree to use instead of `behavior`.
+    #[serde(default)]
+    pub ai: Option<Located<AiBehavior>>
 }
 
+/// A weapon in a level. Its `root` is the player, a named chaser or a collider of a named
+/// weapon like `"flail.head"`, so weapons can hang off each other.
 #[derive(Clone, Debug, Deserialize)]
 pub struct WeaponInstance {
-    pub class: String,
-    pub root: String
+    pub class: Located<String>,
+    pub root: Located<String>,
+    /// Exports the colliders as `"<name>.<collider>"` for other weapons to hang off.
+    #[serde(default)]
+    pub name: Option<Located<String>>
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct WeaponDef {
     pub colliders: Vec<ColliderDef>,
+    #[serde(default)]
     pub chains: Vec<ChainDef>,
-    pub name: String
+    #[serde(default)]
+    pub joints: Vec<JointDef>,
+    pub name: Located<String>,
+    #[serde(skip)]
+    pub file: SourceFile
 }
 
 // TODO: this is a hack, should I use something other than TOML?
@@ -64,8 +327,45 @@ pub struct WeaponDef {
 pub struct ColliderDef {
     pub x: f32,
     pub y: f32,
-    pub props: String,
-    pub name: String
+    pub props: Located<String>,
+    pub name: Located<String>,
+    #[serde(default)]
+    pub shape: ColliderShape
+}
+
+/// The shape of a weapon collider around its position, written like
+/// `shape = { kind = "box", width = 1.0, height = 6.0 }`. The sprite grows to cover it.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum ColliderShape {
+    /// Fills the sprite of the collider's props unless it has a `radius`.
+    Circle {
+        #[serde(default)]
+        x: f32,
+        #[serde(default)]
+        y: f32,
+        #[serde(default)]
+        radius: Option<f32>
+    },
+    Box {
+        #[serde(default)]
+        x: f32,
+        #[serde(default)]
+        y: f32,
+        width: f32,
+        height: f32,
+        /// In radians.
+        #[serde(default)]
+        angle: f32
+    },
+    /// A convex polygon of 3 to 8 points, with no three in a line.
+    Polygon {
+        points: Vec<[f32; 2]>
+    },
+    /// Several shapes stuck together, like a handle and a blade.
+    Compound {
+        parts: Vec<ColliderShape>
+    },
 }
 
 
@@ -75,93 +375,970 @@ pub struct ColliderProps {
     pub angular_damping: f32,
     pub scale: f64,
     pub density: f32,
-    pub restitution: f32
+    pub restitution: f32,
+    /// Multiplier on the damage this collider does to whatever it hits.
+    #[serde(default = "default_damage")]
+    pub damage: f32
 }
 
+/// A chain of `length` links from `object1` to `object2`. Heavy chains have big dense
+/// links, light ropes small and sparse ones.
 #[derive(Clone, Debug, Deserialize)]
 pub struct ChainDef {
-    object1: String,
-    object2: String,
+    object1: Located<String>,
+    object2: Located<String>,
     pub x: f32,
     pub y: f32,
-    pub length: i32
+    pub length: i32,
+    /// Half the width of each square link.
+    #[serde(default = "default_link_size")]
+    pub link_size: f32,
+    #[serde(default = "default_link_density")]
+    pub link_density: f32,
+    /// How far apart the links can get.
+    #[serde(default = "default_link_spacing")]
+    pub link_spacing: f32,
+    #[serde(default = "default_link_texture")]
+    pub link_texture: String,
+    /// Whether the links hit things instead of passing through them.
+    #[serde(default)]
+    pub collide: bool,
+    /// How far the last link can get from `object2`.
+    #[serde(default = "default_final_length")]
+    pub final_length: f32,
+    /// Reaction force above which a link snaps and lets `object2` fly free. Unbreakable if
+    /// left out.
+    #[serde(default)]
+    pub break_force: Option<f32>
 }
 
-pub fn load_level(game: &mut Game, tex: Textures, def: LevelDef,
-        weapons: &HashMap<String, WeaponDef>, collider_props: &HashMap<String, ColliderProps>) {
-    let mut roots = HashMap::<String, GameObjectKey>::new();
+/// Two objects of a weapon held together by something other than a chain.
+#[derive(Clone, Debug, Deserialize)]
+pub struct JointDef {
+    object1: Located<String>,
+    object2: Located<String>,
+    /// Whether the two objects can still hit each other.
+    #[serde(default)]
+    pub collide: bool,
+    pub joint: JointKind
+}
+
+/// How a joint holds its objects together, written like
+/// `joint = { kind = "revolute", anchor = [0.0, 0.0], motor_speed = 10.0 }`. Points are
+/// relative to the root like colliders are.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum JointKind {
+    /// Glues the objects together at `anchor`, bending a little if `frequency` is set.
+    Weld {
+        anchor: [f32; 2],
+        #[serde(default)]
+        frequency: f32,
+        #[serde(default)]
+        damping: f32
+    },
+    /// A rigid rod between the centers of the objects.
+    Rod,
+    /// A spring between the centers of the objects that bounces `frequency` times a second.
+    /// It rests at `length`, or at the distance the objects start at.
+    Spring {
+        frequency: f32,
+        #[serde(default = "default_spring_damping")]
+        damping: f32,
+        #[serde(default)]
+        length: Option<f32>
+    },
+    /// A hinge at `anchor` that turns between the `limits` angles, in radians, if given. A
+    /// motor turns it at `motor_speed` radians a second with up to `max_motor_torque`.
+    Revolute {
+        anchor: [f32; 2],
+        #[serde(default)]
+        limits: Option<[f32; 2]>,
+        #[serde(default)]
+        motor_speed: f32,
+        #[serde(default)]
+        max_motor_torque: f32
+    },
+    /// A piston through `anchor` that slides along `axis` between the `limits`, if given. A
+    /// motor drives it at `motor_speed` with up to `max_motor_force`.
+    Prismatic {
+        anchor: [f32; 2],
+        axis: [f32; 2],
+        #[serde(default)]
+        limits: Option<[f32; 2]>,
+        #[serde(default)]
+        motor_speed: f32,
+        #[serde(default)]
+        max_motor_force: f32
+    },
+    /// A rope from each object's center over a pulley at `ground1` or `ground2`. The pulleys
+    /// stay where they were spawned. Pulling one side in by a length lets the other side out
+    /// by `ratio` times as much.
+    Pulley {
+        ground1: [f32; 2],
+        ground2: [f32; 2],
+        #[serde(default = "default_pulley_ratio")]
+        ratio: f32
+    },
+}
+
+fn default_arena_min() -> [f32; 2] { [-2., -102.] }
+
+fn default_arena_size() -> [f32; 2] { [104., 104.] }
+
+fn default_wall_restitution() -> f32 { 0.6 }
+
+fn default_wall_friction() -> f32 { 0.2 }
 
-    roots.insert(String::from("player"), game.player);
+fn default_link_size() -> f32 { 0.36 }
 
-    let chasers = def.chasers;
-    for chaser_def in chasers.defs {
-        let chaser = make_chaser(game, &tex.chaser, chaser_def, &chasers.props);
+fn default_link_density() -> f32 { 0.01 }
+
+fn default_link_spacing() -> f32 { 1. }
+
+fn default_link_texture() -> String { String::from(DEFAULT_TEXTURE) }
+fn default_pickup_texture() -> String { String::from(PICKUP_TEXTURE) }
+
+fn default_final_length() -> f32 { 0.3 }
+
+fn default_spring_damping() -> f32 { 0.5 }
+
+fn default_pulley_ratio() -> f32 { 1. }
+
+fn default_chaser_health() -> f32 { 20. }
+
+fn default_chaser_texture() -> String { String::from(CHASER_TEXTURE) }
+
+fn default_chase_force() -> f32 { 2000. }
+
+fn default_aggression() -> f32 { 0.5 }
+
+fn default_spawn_count() -> u32 { 1 }
+
+impl Default for ArenaDef {
+    fn default() -> ArenaDef {
+        ArenaDef {
+            min: default_arena_min(),
+            size: default_arena_size(),
+            restitution: default_wall_restitution(),
+            friction: default_wall_friction(),
+            obstacles: Vec::new()
+        }
     }
+}
+
+impl Default for PlayerDef {
+    fn default() -> PlayerDef {
+        PlayerDef {
+            spawn: [50., -50.],
+            scale: 0.5,
+            density: 2.,
+            linear_damping: 2.,
+            restitution: 0.5,
+            health: 100.,
+            texture: String::from(DEFAULT_TEXTURE),
+            max_force: 2000.,
+            loadout: Vec::new()
+        }
+    }
+}
 
-    for NamedChaserDef{def, name} in chasers.named_defs {
-        let chaser = make_chaser(game, &tex.chaser, def, &chasers.props);
-        roots.insert(name, chaser);
+impl Default for CollisionDef {
+    fn default() -> CollisionDef {
+        CollisionDef{friendly_fire: true, hit_owner: true, masks: CollisionMasks::default()}
     }
+}
+
+impl Default for DamageDef {
+    fn default() -> DamageDef {
+        DamageDef{scenery: 0., own_weapons: false}
+    }
+}
+
+impl CollisionMasks {
+    pub fn get(&self, layer: CollisionLayer) -> Option<&Vec<CollisionLayer>> {
+        match layer {
+            CollisionLayer::Player => self.player.as_ref(),
+            CollisionLayer::PlayerWeapon => self.player_weapon.as_ref(),
+            CollisionLayer::Enemy => self.enemy.as_ref(),
+            CollisionLayer::EnemyWeapon => self.enemy_weapon.as_ref(),
+            CollisionLayer::Wall => self.wall.as_ref(),
+            CollisionLayer::Debris => self.debris.as_ref(),
+        }
+    }
+}
+
+impl CollisionDef {
+    pub fn filters(&self) -> CollisionFilters {
+        let lists = |a: CollisionLayer, b: CollisionLayer| self.masks.get(a).is_none_or(|mask| mask.contains(&b));
+        let friendly = |a: CollisionLayer, b: CollisionLayer| matches!((a, b),
+            (CollisionLayer::Player, CollisionLayer::PlayerWeapon) | (CollisionLayer::PlayerWeapon, CollisionLayer::Player) |
+            (CollisionLayer::Enemy, CollisionLayer::EnemyWeapon) | (CollisionLayer::EnemyWeapon, CollisionLayer::Enemy));
+        CollisionFilters::new(|a, b| lists(a, b) && lists(b, a) && (self.friendly_fire || !friendly(a, b)), self.hit_owner)
+    }
+}
+
+impl Chasers {
+    /// The props of an archetype, or the default props for no archetype. None if the
+    /// archetype doesn't exist.
+    pub fn props_for(&self, archetype: Option<&Located<String>>) -> Option<&ChaserProps> {
+        match archetype {
+            Some(archetype) => self.archetypes.get(archetype.as_str()),
+            None => Some(&self.props),
+        }
+    }
+}
+
+impl Default for ColliderShape {
+    fn default() -> ColliderShape {
+        ColliderShape::Circle{x: 0., y: 0., radius: None}
+    }
+}
+
+impl ColliderShape {
+    /// How far the shape reaches from the collider's position.
+    pub fn reach(&self, props: &ColliderProps) -> f32 {
+        let norm = |x: f32, y: f32| (x * x + y * y).sqrt();
+        match *self {
+            ColliderShape::Circle{x, y, radius} => norm(x, y) + radius.unwrap_or_else(|| props.radius()),
+            ColliderShape::Box{x, y, width, height, angle} => {
+                let (sin, cos) = angle.sin_cos();
+                [(1., 1.), (1., -1.), (-1., 1.), (-1., -1.)].iter()
+                    .map(|&(sx, sy)| {
+                        let (cx, cy) = (sx * width / 2., sy * height / 2.);
+                        norm(x + cx * cos - cy * sin, y + cx * sin + cy * cos)
+                    })
+                    .fold(0., f32::max)
+            },
+            ColliderShape::Polygon{ref points} => points.iter().map(|&[x, y]| norm(x, y)).fold(0., f32::max),
+            ColliderShape::Compound{ref parts} => parts.iter().map(|part| part.reach(props)).fold(0., f32::max),
+        }
+    }
+
+    /// Why Box2D couldn't build the shape, if it couldn't.
+    fn problem(&self) -> Option<&'static str> {
+        match *self {
+            ColliderShape::Circle{radius: Some(radius), ..} if radius <= 0. => Some("has no area"),
+            ColliderShape::Box{width, height, ..} if width <= 0. || height <= 0. => Some("has no area"),
+            ColliderShape::Polygon{ref points} => polygon_problem(points),
+            ColliderShape::Compound{ref parts} if parts.is_empty() => Some("has no parts"),
+            ColliderShape::Compound{ref parts} => parts.iter().find_map(|part| part.problem()),
+            _ => None,
+        }
+    }
+}
+
+impl ColliderProps {
+    /// Radius of a circle that fills the sprite.
+    pub fn radius(&self) -> f32 {
+        self.scale as f32 * SHIP_RADIUS
+    }
+}
+
+impl JointDef {
+    /// Why Box2D couldn't build the joint, if it couldn't.
+    fn problem(&self) -> Option<&'static str> {
+        let backwards = |limits: Option<[f32; 2]>| limits.is_some_and(|[lower, upper]| lower > upper);
+        if *self.object1 == *self.object2 {
+            return Some("joins an object to itself");
+        }
+        match self.joint {
+            JointKind::Spring{frequency, ..} if frequency <= 0. => Some("spring needs a frequency above 0"),
+            JointKind::Spring{length: Some(length), ..} if length <= 0. => Some("spring needs a length above 0"),
+            JointKind::Revolute{limits, ..} if backwards(limits) => Some("lower limit is above the upper limit"),
+            JointKind::Prismatic{axis: [0., 0.], ..} => Some("piston needs an axis"),
+            JointKind::Prismatic{limits, ..} if backwards(limits) => Some("lower limit is above the upper limit"),
+            JointKind::Pulley{ratio, ..} if ratio <= f32::EPSILON => Some("pulley needs a ratio above 0"),
+            _ => None,
+        }
+    }
+}
+
+impl WeaponInstance {
+    /// The name another weapon can use as its root to hang off `collider`, if this weapon
+    /// has a name.
+    pub fn qualified(&self, collider: &str) -> Option<String> {
+        self.name.as_ref().map(|name| format!("{}.{}", name.value, collider))
+    }
+}
 
-    for weapon in def.weapons {
-        let weapon_def = match weapons.get(&weapon.class) {
-            Some(p) => p,
-            None => {
-                println!("Could not find weapon class {}, skipping weapon", weapon.class);
-                continue;
+impl WaveSpawnDef {
+    /// Where each chaser of the spawn starts, in a ring around `point` wide enough that
+    /// chasers of `radius` don't overlap.
+    pub fn positions(&self, point: [f32; 2], radius: f32) -> Vec<b2::Vec2> {
+        let center = b2::Vec2{x: point[0], y: point[1]};
+        if self.count <= 1 {
+            return vec![center; self.count as usize];
+        }
+        let step = 2. * f32::consts::PI / self.count as f32;
+        let distance = radius / (step / 2.).sin();
+        (0 .. self.count)
+            .map(|i| {
+                let angle = step * i as f32;
+                center + b2::Vec2{x: angle.cos(), y: angle.sin()} * distance
+            })
+            .collect()
+    }
+}
+
+impl ArenaDef {
+    /// Lower left and upper right corners of the area inside the walls.
+    pub fn bounds(&self) -> (b2::Vec2, b2::Vec2) {
+        let min = b2::Vec2{x: self.min[0], y: self.min[1]};
+        (min, min + b2::Vec2{x: self.size[0], y: self.size[1]})
+    }
+
+    /// Every obstacle Box2D would refuse to build.
+    pub fn check(&self, file: &SourceFile) -> Vec<LoadError> {
+        let mut errors = Vec::new();
+        let mut invalid = |description: String| errors.push(LoadError::InvalidShape {
+            at: Location{path: file.path.clone(), position: None},
+            description
+        });
+        if self.size[0] <= 0. || self.size[1] <= 0. {
+            invalid(format!("arena size {:?} must be positive", self.size));
+        }
+        for (i, obstacle) in self.obstacles.iter().enumerate() {
+            let problem = match obstacle.shape {
+                ShapeDef::Box{width, height, ..} if width <= 0. || height <= 0. => "has no area",
+                ShapeDef::Circle{radius, ..} if radius <= 0. => "has no area",
+                ShapeDef::Polygon{ref points} => match polygon_problem(points) {
+                    Some(problem) => problem,
+                    None => continue,
+                },
+                ShapeDef::Chain{ref points, closed: false} if points.len() < 2 => "needs at least 2 points",
+                ShapeDef::Chain{ref points, closed: true} if points.len() < 3 => "needs at least 3 points",
+                ShapeDef::Chain{ref points, closed} if too_close(points, closed) => "has points on top of each other",
+                _ => continue,
+            };
+            invalid(format!("obstacle {} {}", i + 1, problem));
+        }
+        errors
+    }
+}
+
+/// Box2D's `b2_linearSlop`: points closer than this count as the same point.
+const LINEAR_SLOP: f32 = 0.005;
+
+/// Whether any two neighbouring points, including the last and first if `closed`, are too
+/// close for Box2D to tell apart.
+fn too_close(points: &[[f32; 2]], closed: bool) -> bool {
+    let pairs = points.len() - if closed { 0 } else { 1 };
+    (0 .. pairs).any(|i| {
+        let ([x1, y1], [x2, y2]) = (points[i], points[(i + 1) % points.len()]);
+        (x2 - x1) * (x2 - x1) + (y2 - y1) * (y2 - y1) <= LINEAR_SLOP * LINEAR_SLOP
+    })
+}
+
+/// Why Box2D couldn't build a polygon with exactly these points, if it couldn't. It asserts on
+/// polygons with no area and quietly replaces concave ones with their convex hull.
+fn polygon_problem(points: &[[f32; 2]]) -> Option<&'static str> {
+    if points.len() < 3 || points.len() > 8 {
+        return Some("needs 3 to 8 points");
+    }
+    if too_close(points, true) {
+        return Some("has points on top of each other");
+    }
+    let n = points.len();
+    let mut crosses = Vec::with_capacity(n);
+    let mut turned: f32 = 0.;
+    for i in 0 .. n {
+        let ([x0, y0], [x1, y1], [x2, y2]) = (points[i], points[(i + 1) % n], points[(i + 2) % n]);
+        let (ax, ay, bx, by) = (x1 - x0, y1 - y0, x2 - x1, y2 - y1);
+        let cross = ax * by - ay * bx;
+        if cross.abs() <= LINEAR_SLOP * (ax * ax + ay * ay).sqrt() * (bx * bx + by * by).sqrt() {
+            return Some("has three points in a line");
+        }
+        crosses.push(cross);
+        turned += cross.atan2(ax * bx + ay * by);
+    }
+    // a star turns the same way at every corner, but goes round more than once
+    let same_way = crosses.iter().all(|&cross| cross > 0.) || crosses.iter().all(|&cross| cross < 0.);
+    if !same_way || turned.abs() > 3. * f32::consts::PI {
+        return Some("isn't convex");
+    }
+    None
+}
+
+fn default_damage() -> f32 { 1. }
+
+impl<T> Deref for Located<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Located<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Located<T>, D::Error> {
+        let spanned = toml::Spanned::<T>::deserialize(deserializer)?;
+        Ok(Located{span: spanned.span(), value: spanned.into_inner()})
+    }
+}
+
+fn bad_reference(file: &SourceFile, kind: Reference, name: &Located<String>) -> LoadError {
+    LoadError::BadReference {
+        at: Location::new(file, name.span),
+        kind,
+        name: name.value.clone()
+    }
+}
+
+/// Reports every name in `names` that's `reserved` or was used earlier in the list.
+fn check_unique<'a, I>(file: &SourceFile, reserved: &str, names: I) -> Vec<LoadError>
+        where I: Iterator<Item = &'a Located<String>> {
+    let mut errors = Vec::new();
+    let mut seen: Vec<&Located<String>> = Vec::new();
+    for name in names {
+        if name.value == reserved {
+            errors.push(LoadError::DuplicateName {
+                at: Location::new(file, name.span),
+                first: None,
+                name: name.value.clone()
+            });
+        } else if let Some(first) = seen.iter().find(|first| first.value == name.value) {
+            errors.push(LoadError::DuplicateName {
+                at: Location::new(file, name.span),
+                first: Some(Location::new(file, first.span)),
+                name: name.value.clone()
+            });
+        } else {
+            seen.push(name);
+        }
+    }
+    errors
+}
+
+/// Every weapon class, root, chaser archetype or spawn point in the level that doesn't exist, every chaser or weapon
+/// name that's used twice and every obstacle that can't be built. Weapons themselves are checked by `check_weapon`.
+pub fn check_level(def: &LevelDef, weapons: &HashMap<String, WeaponDef>) -> Vec<LoadError> {
+    let mut errors = def.arena.check(&def.file);
+    errors.extend(check_unique(&def.file, "player", def.chasers.named_defs.iter().map(|named| &named.name)));
+
+    let spawns = def.waves.iter().flat_map(|wave| &wave.spawns);
+    let archetypes = def.chasers.defs.iter().map(|chaser| &chaser.archetype)
+        .chain(def.chasers.named_defs.iter().map(|named| &named.def.archetype))
+        .chain(spawns.clone().map(|spawn| &spawn.archetype));
+    for archetype in archetypes.flatten() {
+        if !def.chasers.archetypes.contains_key(archetype.as_str()) {
+            errors.push(bad_reference(&def.file, Reference::Archetype, archetype));
+        }
+    }
+    let mut trees: Vec<&Located<AiBehavior>> = Some(&def.chasers.props).into_iter()
+        .chain(def.chasers.archetypes.values())
+        .filter_map(|props| props.ai.as_ref())
+        .collect();
+    // in file order rather than the archetype map's
+    trees.sort_by_key(|tree| tree.span);
+    for tree in trees {
+        errors.extend(tree.problems().into_iter().map(|description| LoadError::InvalidBehavior {
+            at: Location::new(&def.file, tree.span),
+            description
+        }));
+    }
+    for spawn in spawns {
+        if !def.spawn_points.contains_key(spawn.at.as_str()) {
+            errors.push(bad_reference(&def.file, Reference::SpawnPoint, &spawn.at));
+        }
+        if let Some(ref weapon) = spawn.weapon {
+            if !weapons.contains_key(weapon.as_str()) {
+                errors.push(bad_reference(&def.file, Reference::WeaponClass, weapon));
             }
-        };
-        let root = match roots.get(&weapon.root) {
-            Some(p) => p,
-            None => {
-                println!("Could not find root object {}, skipping weapon", weapon.root);
-                continue;
+        }
+    }
+
+    errors.extend(check_unique(&def.file, "player", def.weapons.iter().flat_map(|weapon| &weapon.name)));
+    let classes = def.weapons.iter().map(|weapon| &weapon.class)
+        .chain(&def.player.loadout)
+        .chain(def.pickups.iter().map(|pickup| &pickup.class));
+    for class in classes {
+        if !weapons.contains_key(class.as_str()) {
+            errors.push(bad_reference(&def.file, Reference::WeaponClass, class));
+        }
+    }
+    // roots that are never defined, or that only hang off each other
+    for weapon in weapon_order(def, weapons).1 {
+        errors.push(bad_reference(&def.file, Reference::Root, &weapon.root));
+    }
+    errors
+}
+
+/// The level's weapons, each after the weapon it hangs off but otherwise in file order, and
+/// the weapons whose roots can't be found.
+fn weapon_order<'a>(def: &'a LevelDef, weapons: &HashMap<String, WeaponDef>)
+        -> (Vec<&'a WeaponInstance>, Vec<&'a WeaponInstance>) {
+    let mut roots: Vec<String> = vec![String::from("player")];
+    roots.extend(def.chasers.named_defs.iter().map(|named| named.name.value.clone()));
+
+    let mut ordered = Vec::new();
+    let mut pending: Vec<&WeaponInstance> = def.weapons.iter().collect();
+    loop {
+        let (ready, rest): (Vec<&WeaponInstance>, Vec<&WeaponInstance>) =
+            pending.into_iter().partition(|weapon| roots.contains(&weapon.root.value));
+        if ready.is_empty() {
+            return (ordered, rest);
+        }
+        for weapon in &ready {
+            if let Some(weapon_def) = weapons.get(weapon.class.as_str()) {
+                roots.extend(weapon_def.colliders.iter().filter_map(|collider| weapon.qualified(&collider.name)));
+            }
+        }
+        ordered.extend(ready);
+        pending = rest;
+    }
+}
+
+/// Every props set or chain or joint end in the weapon that doesn't exist, every collider
+/// name that's used twice and every collider, chain or joint that can't be built.
+pub fn check_weapon(def: &WeaponDef, collider_props: &HashMap<String, ColliderProps>) -> Vec<LoadError> {
+    let mut errors = check_unique(&def.file, "root", def.colliders.iter().map(|collider| &collider.name));
+    for collider in &def.colliders {
+        if !collider_props.contains_key(&*collider.props) {
+            errors.push(bad_reference(&def.file, Reference::Props, &collider.props));
+        }
+        if let Some(problem) = collider.shape.problem() {
+            errors.push(LoadError::InvalidShape {
+                at: Location::new(&def.file, collider.name.span),
+                description: format!("collider `{}` {}", *collider.name, problem)
+            });
+        }
+    }
+
+    let mut objects = vec!["root"];
+    objects.extend(def.colliders.iter().map(|collider| collider.name.as_str()));
+    for chain_def in &def.chains {
+        for end in &[&chain_def.object1, &chain_def.object2] {
+            if !objects.contains(&end.as_str()) {
+                errors.push(bad_reference(&def.file, Reference::ChainEnd, end));
             }
+        }
+        let problem = if chain_def.link_size <= 0. {
+            Some("chain links need a size above 0")
+        } else if chain_def.link_spacing <= 0. || chain_def.final_length <= 0. {
+            Some("chain links need to be spaced more than 0 apart")
+        } else if chain_def.break_force.is_some_and(|force| force <= 0.) {
+            Some("chain needs a break force above 0")
+        } else {
+            None
         };
-        load_weapon(game, &tex, weapon_def, collider_props, *root);
+        if let Some(problem) = problem {
+            errors.push(LoadError::InvalidShape {
+                at: Location::new(&def.file, chain_def.object1.span),
+                description: String::from(problem)
+            });
+        }
+    }
+    for joint_def in &def.joints {
+        for end in &[&joint_def.object1, &joint_def.object2] {
+            if !objects.contains(&end.as_str()) {
+                errors.push(bad_reference(&def.file, Reference::JointEnd, end));
+            }
+        }
+        if let Some(problem) = joint_def.problem() {
+            errors.push(LoadError::InvalidJoint {
+                at: Location::new(&def.file, joint_def.object1.span),
+                description: String::from(problem)
+            });
+        }
+    }
+    errors
+}
+
+/// Spawns the level's arena, player, chasers and weapons. Nothing is spawned if anything in the level or the
+/// weapons it uses is wrong.
+pub fn load_level(sim: &mut Simulation, def: &LevelDef,
+        weapons: &HashMap<String, WeaponDef>, collider_props: &HashMap<String, ColliderProps>)
+        -> Result<(), Vec<LoadError>> {
+    let mut errors = check_level(def, weapons);
+    let mut checked = Vec::new();
+    let wave_weapons = def.waves.iter().flat_map(|wave| &wave.spawns).flat_map(|spawn| &spawn.weapon);
+    let classes = def.weapons.iter().map(|weapon| &weapon.class)
+        .chain(wave_weapons)
+        .chain(&def.player.loadout)
+        .chain(def.pickups.iter().map(|pickup| &pickup.class));
+    for class in classes {
+        if let Some(weapon_def) = weapons.get(class.as_str()) {
+            if !checked.contains(&weapon_def.name.value) {
+                checked.push(weapon_def.name.value.clone());
+                errors.extend(check_weapon(weapon_def, collider_props));
+            }
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    sim.collision = def.collision.filters();
+    sim.damage = def.damage;
+    sim.player = make_player(sim, &def.player);
+    make_arena(&mut sim.world, &def.arena, &sim.collision.filter(CollisionLayer::Wall, None));
+
+    let mut roots = HashMap::<String, GameObjectKey>::new();
+
+    roots.insert(String::from("player"), sim.player);
+
+    let chasers = &def.chasers;
+    for chaser_def in &chasers.defs {
+        make_chaser(sim, chaser_def, chasers.props_for(chaser_def.archetype.as_ref()).unwrap());
+    }
+
+    for named in &chasers.named_defs {
+        let chaser = make_chaser(sim, &named.def, chasers.props_for(named.def.archetype.as_ref()).unwrap());
+        roots.insert(named.name.value.clone(), chaser);
+    }
+
+    for weapon in weapon_order(def, weapons).0 {
+        let (_, colliders) = load_weapon(sim, &weapons[&*weapon.class], collider_props, roots[&*weapon.root])?;
+        for (name, key) in colliders {
+            if let Some(qualified) = weapon.qualified(&name) {
+                roots.insert(qualified, key);
+            }
+        }
+    }
+
+    for (index, pickup) in def.pickups.iter().enumerate() {
+        make_pickup(sim, pickup, index);
+    }
+
+    sim.loadout = Loadout::new(def.player.loadout.iter().map(|class| class.value.clone()).collect());
+    if let Some(class) = sim.loadout.classes.first().cloned() {
+        let (id, _) = load_weapon(sim, &weapons[&class], collider_props, sim.player)?;
+        sim.loadout.equipped = Some(id);
+    }
+    Ok(())
+}
+
+/// Spawns every chaser of a wave along with their weapons. The wave must come from a level
+/// that `load_level` accepted.
+pub fn spawn_wave(sim: &mut Simulation, defs: &Definitions, wave: &WaveDef) {
+    let chasers = &defs.level.chasers;
+    for spawn in &wave.spawns {
+        let props = chasers.props_for(spawn.archetype.as_ref()).unwrap();
+        let point = defs.level.spawn_points[spawn.at.as_str()];
+        for position in spawn.positions(point, props.scale as f32 * SHIP_RADIUS) {
+            let chaser_def = ChaserDef{x: position.x, y: position.y, archetype: None};
+            let chaser = make_chaser(sim, &chaser_def, props);
+            if let Some(ref weapon) = spawn.weapon {
+                load_weapon(sim, &defs.weapons[weapon.as_str()], &defs.props, chaser)
+                    .expect("wave weapons are checked when the level loads");
+            }
+        }
     }
 }
 
+/// Spawns a weapon attached to `root` and returns it along with its colliders by name. Nothing
+/// is spawned if the weapon refers to props or objects that don't exist.
 pub fn load_weapon(
-        game: &mut Game,
-        tex: &Textures,
+        sim: &mut Simulation,
         def: &WeaponDef,
         collider_props: &HashMap<String, ColliderProps>,
         root: GameObjectKey
-    ) {
+    ) -> Result<(WeaponId, HashMap<String, GameObjectKey>), Vec<LoadError>> {
+    let errors = check_weapon(def, collider_props);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let part = WeaponPart{weapon: sim.new_weapon_id(), root};
     let mut objects = HashMap::new();
-    objects.insert(String::from("root"), root);
+    objects.insert("root", root);
+    let mut colliders = HashMap::new();
 
-    let root_pos = game.body(root).unwrap().position().clone();
+    let root_pos = sim.body(root).unwrap().position().clone();
 
-    for ref collider in &def.colliders {
-        let props = match collider_props.get(&collider.props) {
-            Some(p) => p,
-            None => {
-                println!("Could not find collider property set {}, skipping collider", collider.props);
-                continue;
+    for collider in &def.colliders {
+        let props = &collider_props[&*collider.props];
+        let key = make_ball(sim, collider, props, root_pos, part);
+        objects.insert(collider.name.as_str(), key);
+        colliders.insert(collider.name.value.clone(), key);
+    }
+
+    for chain_def in &def.chains {
+        let object1 = objects[chain_def.object1.as_str()];
+        let object2 = objects[chain_def.object2.as_str()];
+        make_chain(sim, object1, object2, chain_def, root_pos, part);
+    }
+
+    for joint_def in &def.joints {
+        let object1 = objects[joint_def.object1.as_str()];
+        let object2 = objects[joint_def.object2.as_str()];
+        make_joint(sim, object1, object2, joint_def, root_pos);
+    }
+    Ok((part.weapon, colliders))
+}
+
+/// The player and every chaser, weapon collider and chain, including those spawned by waves,
+/// that would start outside the arena walls.
+pub fn check_arena(defs: &Definitions) -> Vec<LoadError> {
+    let level = &defs.level;
+    let (min, max) = level.arena.bounds();
+    let inside = |x: f32, y: f32, radius: f32|
+        x - radius > min.x && x + radius < max.x && y - radius > min.y && y + radius < max.y;
+    let chasers = &level.chasers;
+    // chasers with unknown archetypes are reported by check_level
+    let chaser_radius = |archetype: Option<&Located<String>>|
+        chasers.props_for(archetype).map_or(0., |props| props.scale as f32 * SHIP_RADIUS);
+
+    let mut errors = Vec::new();
+    let player = &level.player;
+    let [player_x, player_y] = player.spawn;
+    if !inside(player_x, player_y, player.scale as f32 * SHIP_RADIUS) {
+        errors.push(LoadError::OutsideArena {
+            at: Location{path: level.file.path.clone(), position: None},
+            description: String::from("player"),
+            position: (player_x, player_y)
+        });
+    }
+    let mut roots = HashMap::new();
+    roots.insert(String::from("player"), (player_x, player_y));
+    for named in &chasers.named_defs {
+        if !inside(named.def.x, named.def.y, chaser_radius(named.def.archetype.as_ref())) {
+            errors.push(LoadError::OutsideArena {
+                at: Location::new(&level.file, named.name.span),
+                description: format!("chaser `{}`", *named.name),
+                position: (named.def.x, named.def.y)
+            });
+        }
+        roots.insert(named.name.value.clone(), (named.def.x, named.def.y));
+    }
+    for (i, def) in chasers.defs.iter().enumerate() {
+        if !inside(def.x, def.y, chaser_radius(def.archetype.as_ref())) {
+            errors.push(LoadError::OutsideArena {
+                at: Location{path: level.file.path.clone(), position: None},
+                description: format!("chaser {}", i + 1),
+                position: (def.x, def.y)
+            });
+        }
+    }
+
+    for pickup in &level.pickups {
+        if !inside(pickup.x, pickup.y, PICKUP_RADIUS) {
+            errors.push(LoadError::OutsideArena {
+                at: Location::new(&level.file, pickup.class.span),
+                description: format!("`{}` pickup", *pickup.class),
+                position: (pickup.x, pickup.y)
+            });
+        }
+    }
+
+    // every weapon with the name and position of what it's attached to
+    let mut armed = Vec::new();
+    for weapon in weapon_order(level, &defs.weapons).0 {
+        let (x, y) = roots[weapon.root.as_str()];
+        if let Some(weapon_def) = defs.weapons.get(weapon.class.as_str()) {
+            for collider in &weapon_def.colliders {
+                if let Some(qualified) = weapon.qualified(&collider.name) {
+                    roots.insert(qualified, (x + collider.x, y + collider.y));
+                }
             }
-        };
-        let name = collider.name.clone();
-        let key = make_ball(game, &tex.default, &collider, props, root_pos);
-        objects.insert(name, key);
+        }
+        armed.push((&weapon.class, format!("`{}`", *weapon.root), x, y));
+    }
+    // any loadout weapon can be the one equipped at the spawn
+    for class in &player.loadout {
+        armed.push((class, String::from("the player"), player_x, player_y));
     }
 
-    for ref chain_def in &def.chains {
-        let object1 = match objects.get(&chain_def.object1) {
-            Some(o) => o,
-            None => {
-                println!("Could not find object {}, skipping chain", chain_def.object1);
-                continue;
+    for (i, wave) in level.waves.iter().enumerate() {
+        for spawn in &wave.spawns {
+            let (point, radius) = match (level.spawn_points.get(spawn.at.as_str()), chasers.props_for(spawn.archetype.as_ref())) {
+                (Some(&point), Some(props)) => (point, props.scale as f32 * SHIP_RADIUS),
+                _ => continue,
+            };
+            let description = format!("chaser of wave {} at `{}`", i + 1, *spawn.at);
+            for position in spawn.positions(point, radius) {
+                if !inside(position.x, position.y, radius) {
+                    errors.push(LoadError::OutsideArena {
+                        at: Location::new(&level.file, spawn.at.span),
+                        description: description.clone(),
+                        position: (position.x, position.y)
+                    });
+                }
+                if let Some(ref weapon) = spawn.weapon {
+                    armed.push((weapon, description.clone(), position.x, position.y));
+                }
             }
+        }
+    }
+
+    for (class, root, root_x, root_y) in armed {
+        let weapon_def = match defs.weapons.get(class.as_str()) {
+            Some(weapon_def) => weapon_def,
+            None => continue,
         };
-        let object2 = match objects.get(&chain_def.object2) {
-            Some(o) => o,
-            None => {
-                println!("Could not find object {}, skipping chain", chain_def.object2);
-                continue;
+        let mut outside = |description: String, x: f32, y: f32| {
+            errors.push(LoadError::OutsideArena {
+                at: Location::new(&level.file, class.span),
+                description: format!("{} of `{}` on {}", description, class.value, root),
+                position: (x, y)
+            });
+        };
+        for collider in &weapon_def.colliders {
+            // colliders with unknown props are reported by check_weapon
+            let reach = defs.props.get(collider.props.as_str()).map_or(0., |props| collider.shape.reach(props));
+            let (x, y) = (root_x + collider.x, root_y + collider.y);
+            if !inside(x, y, reach) {
+                outside(format!("collider `{}`", *collider.name), x, y);
+            }
+        }
+        for chain in &weapon_def.chains {
+            let (x, y) = (root_x + chain.x, root_y + chain.y);
+            if !inside(x, y, 0.) {
+                outside(format!("chain from `{}` to `{}`", *chain.object1, *chain.object2), x, y);
+            }
+        }
+    }
+    errors
+}
+
+impl Definitions {
+    /// Every problem with the level and every weapon, whether or not the level uses it.
+    pub fn check(&self) -> Vec<LoadError> {
+        let mut errors = self.check_duplicates();
+        errors.extend(check_level(&self.level, &self.weapons));
+        let mut weapons: Vec<&WeaponDef> = self.weapons.values().collect();
+        weapons.sort_by(|a, b| a.file.path.cmp(&b.file.path));
+        for weapon in weapons {
+            errors.extend(check_weapon(weapon, &self.props));
+        }
+        errors.extend(check_arena(self));
+        errors
+    }
+
+    /// Every weapon name that's defined in more than one file.
+    pub fn check_duplicates(&self) -> Vec<LoadError> {
+        self.duplicate_weapons.iter()
+            .map(|def| {
+                let first = &self.weapons[&*def.name];
+                LoadError::DuplicateName {
+                    at: Location::new(&def.file, def.name.span),
+                    first: Some(Location::new(&first.file, first.name.span)),
+                    name: def.name.value.clone()
+                }
+            })
+            .collect()
+    }
+}
+
+impl SourceFile {
+    pub fn read(path: &Path) -> Result<SourceFile, LoadError> {
+        let path_string = path.to_string_lossy().into_owned();
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(SourceFile{path: path_string, contents}),
+            Err(error) => Err(LoadError::Io{path: path_string, error}),
+        }
+    }
+
+    fn parse<T: for<'de> Deserialize<'de>>(&self) -> Result<T, LoadError> {
+        toml::from_str(&self.contents).map_err(|e| LoadError::parse(self, e))
+    }
+}
+
+impl Sources {
+    /// Reads the level, the props and every file in the weapons directory, reporting every
+    /// file that couldn't be read.
+    pub fn read(level: &Path, props: &Path, weapons_dir: &Path) -> Result<Sources, Vec<LoadError>> {
+        let mut errors = Vec::new();
+        let mut weapons = Vec::new();
+        match fs::read_dir(weapons_dir) {
+            Ok(entries) => for entry in entries {
+                match entry.map_err(|error| LoadError::Io{path: weapons_dir.to_string_lossy().into_owned(), error})
+                        .and_then(|entry| SourceFile::read(&entry.path())) {
+                    Ok(file) => weapons.push(file),
+                    Err(e) => errors.push(e),
+                }
+            },
+            Err(error) => errors.push(LoadError::Io{path: weapons_dir.to_string_lossy().into_owned(), error}),
+        }
+        // directory order isn't stable, and replays need the same weapons on every machine
+        weapons.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let level = SourceFile::read(level).map_err(|e| errors.push(e)).ok();
+        let props = SourceFile::read(props).map_err(|e| errors.push(e)).ok();
+        match (level, props) {
+            (Some(level), Some(props)) if errors.is_empty() => Ok(Sources{level, props, weapons}),
+            _ => Err(errors),
+        }
+    }
+
+    /// Parses every file, reporting every one that isn't valid.
+    pub fn parse(&self) -> Result<Definitions, Vec<LoadError>> {
+        let mut errors = Vec::new();
+        let level = self.level.parse::<LevelDef>()
+            .map(|def| LevelDef{file: self.level.clone(), .. def})
+            .map_err(|e| errors.push(e))
+            .ok();
+        let props = self.props.parse().map_err(|e| errors.push(e)).ok();
+
+        let mut weapons: HashMap<String, WeaponDef> = HashMap::new();
+        let mut duplicate_weapons = Vec::new();
+        for file in &self.weapons {
+            match file.parse::<WeaponDef>() {
+                Ok(def) => {
+                    let def = WeaponDef{file: file.clone(), .. def};
+                    if weapons.contains_key(&*def.name) {
+                        duplicate_weapons.push(def);
+                    } else {
+                        weapons.insert(def.name.value.clone(), def);
+                    }
+                },
+                Err(e) => errors.push(e),
             }
+        }
+
+        match (level, props) {
+            (Some(level), Some(props)) if errors.is_empty() =>
+                Ok(Definitions{level, props, weapons, duplicate_weapons}),
+            _ => Err(errors),
+        }
+    }
+
+    /// Builds a fresh simulation of the level these files describe.
+    pub fn build(&self, tick_rate: f64, seed: u32) -> Result<Simulation, Vec<LoadError>> {
+        let defs = self.parse()?;
+        let duplicates = defs.check_duplicates();
+        if !duplicates.is_empty() {
+            return Err(duplicates);
+        }
+        let mut sim = Simulation::new(tick_rate, seed);
+        load_level(&mut sim, &defs.level, &defs.weapons, &defs.props)?;
+        sim.set_level(self.clone(), defs);
+        Ok(sim)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use swingyships::load_error::LoadError;
+    use swingyships::testing::read_level;
+    use super::{check_arena, SourceFile};
+
+    #[test]
+    fn loadout_weapons_are_checked_against_the_arena() {
+        let mut sources = read_level("level.toml");
+        sources.level = SourceFile {
+            path: String::from("test_level.toml"),
+            contents: String::from(r#"
+[player]
+  spawn = [50.0, -95.0]
+  loadout = ["whip"]
+
+[chasers.props]
+  linear_damping = 1.0
+  scale = 1.0
+  density = 1.0
+  restitution = 0.5
+"#)
         };
-        make_chain(game, *object1, *object2, &tex.default, chain_def, root_pos);
+        let defs = sources.parse().expect("could not parse the level");
+        let outside: Vec<String> = check_arena(&defs).iter().filter_map(|error| match *error {
+            LoadError::OutsideArena{ref description, ..} => Some(description.clone()),
+            _ => None,
+        }).collect();
+        assert_eq!(outside, [
+            "collider `whip` of `whip` on the player",
+            "chain from `root` to `whip` of `whip` on the player"
+        ]);
     }
 }
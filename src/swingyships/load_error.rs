@@ -0,0 +1,138 @@
+extern crate toml;
+
+use swingyships::level_loader::SourceFile;
+
+use std::fmt;
+use std::io;
+
+/// A place in a level, props or weapon file.
+#[derive(Clone, Debug)]
+pub struct Location {
+    pub path: String,
+    /// One-based line and column, when the problem can be pinned down.
+    pub position: Option<(usize, usize)>
+}
+
+/// What kind of thing a bad name was supposed to refer to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reference {
+    WeaponClass,
+    Root,
+    Props,
+    ChainEnd,
+    JointEnd,
+    Archetype,
+    SpawnPoint,
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    /// A file or directory couldn't be read.
+    Io {
+        path: String,
+        error: io::Error
+    },
+    /// A file isn't valid TOML or doesn't have the fields it should.
+    Parse {
+        at: Location,
+        message: String
+    },
+    /// A name that doesn't match anything it could refer to.
+    BadReference {
+        at: Location,
+        kind: Reference,
+        name: String
+    },
+    /// A name that's already been used for something else of the same kind, or is reserved
+    /// when there's no `first`.
+    DuplicateName {
+        at: Location,
+        first: Option<Location>,
+        name: String
+    },
+    /// A shape Box2D can't build.
+    InvalidShape {
+        at: Location,
+        description: String
+    },
+    /// A weapon joint Box2D can't build.
+    InvalidJoint {
+        at: Location,
+        description: String
+    },
+    /// A behavior tree that would panic or never get anywhere.
+    InvalidBehavior {
+        at: Location,
+        description: String
+    },
+    /// Something that would spawn beyond the arena walls.
+    OutsideArena {
+        at: Location,
+        description: String,
+        position: (f32, f32)
+    },
+}
+
+impl Location {
+    /// Points at the byte range `span` of `file`.
+    pub fn new(file: &SourceFile, span: (usize, usize)) -> Location {
+        let before = &file.contents[.. span.0.min(file.contents.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+        Location{path: file.path.clone(), position: Some((line, column))}
+    }
+}
+
+impl LoadError {
+    pub fn parse(file: &SourceFile, error: toml::de::Error) -> LoadError {
+        // toml counts lines and columns from zero
+        let position = error.line_col().map(|(line, column)| (line + 1, column + 1));
+        LoadError::Parse {
+            at: Location{path: file.path.clone(), position},
+            message: error.to_string()
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.position {
+            Some((line, column)) => write!(f, "{}:{}:{}", self.path, line, column),
+            None => write!(f, "{}", self.path),
+        }
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match *self {
+            Reference::WeaponClass => "weapon class",
+            Reference::Root => "root object",
+            Reference::Props => "collider property set",
+            Reference::ChainEnd => "chain end object",
+            Reference::JointEnd => "joint end object",
+            Reference::Archetype => "chaser archetype",
+            Reference::SpawnPoint => "spawn point",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Io{ref path, ref error} => write!(f, "{}: could not read: {}", path, error),
+            LoadError::Parse{ref at, ref message} => write!(f, "{}: could not parse: {}", at, message),
+            LoadError::BadReference{ref at, kind, ref name} => write!(f, "{}: unknown {} `{}`", at, kind, name),
+            LoadError::DuplicateName{ref at, first: Some(ref first), ref name} =>
+                write!(f, "{}: `{}` is already defined at {}", at, name, first),
+            LoadError::DuplicateName{ref at, first: None, ref name} =>
+                write!(f, "{}: `{}` is a reserved name", at, name),
+            LoadError::InvalidShape{ref at, ref description} => write!(f, "{}: {}", at, description),
+            LoadError::InvalidJoint{ref at, ref description} => write!(f, "{}: {}", at, description),
+            LoadError::InvalidBehavior{ref at, ref description} => write!(f, "{}: behavior tree {}", at, description),
+            LoadError::OutsideArena{ref at, ref description, position: (x, y)} =>
+                write!(f, "{}: {} would spawn at ({}, {}), outside the arena", at, description, x, y),
+        }
+    }
+}
@@ -1,4 +1,11 @@
 pub mod game;
+pub mod ai;
 pub mod objects;
 pub mod level_loader;
+pub mod load_error;
 pub mod physics;
+pub mod simulation;
+pub mod replay;
+pub mod snapshot;
+#[cfg(test)]
+pub mod testing;
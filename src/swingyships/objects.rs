@@ -1,74 +1,154 @@
 extern crate wrapped2d;
-extern crate ai_behavior;
-extern crate sprite;
-extern crate glium_graphics;
-extern crate slotmap;
 
-use glium_graphics::Texture;
-use slotmap::SlotMap;
-
-use swingyships::game::{Game, GameObject, GameObjectType, GameObjectKey};
-use swingyships::level_loader::{ChaserDef, ChaserProps, ColliderDef, ColliderProps, ChainDef};
+use swingyships::game::{GameObject, GameObjectType, GameObjectKey, Appearance, WeaponPart};
+use swingyships::simulation::Simulation;
+use swingyships::physics::{GameUserData, ObjectRole};
+use swingyships::ai::Brain;
+use swingyships::level_loader::{ArenaDef, ShapeDef, PlayerDef, PickupDef, ChaserDef, ChaserProps, ColliderDef, ColliderProps, ColliderShape, ChainDef, JointDef, JointKind};
 
 use wrapped2d::b2;
-use wrapped2d::user_data::NoUserData;
 use wrapped2d::handle::TypedHandle;
-use std::rc::Rc;
-
-use sprite::*;
-use ai_behavior::{
-    Action,
-    Sequence,
-    Wait,
-    WaitForever,
-    While,
-};
-
-fn draw_scale(scale: f64) -> f64 {
-     -(1. - scale)
-}
+
+pub const DEFAULT_TEXTURE: &str = "rust.png";
+pub const CHASER_TEXTURE: &str = "rust_red.png";
+pub const PICKUP_TEXTURE: &str = "pickup.png";
+/// Radius of the player or a chaser at scale 1, matching their textures.
+pub const SHIP_RADIUS: f32 = 7.2;
+/// Radius of the sensor around a weapon pickup.
+pub const PICKUP_RADIUS: f32 = 2.5;
+/// Sprite scale of a chain link per unit of its `link_size`.
+const LINK_SCALE: f64 = 0.08 / 0.36;
 
 pub fn make_player(
-        world: &mut b2::World::<NoUserData>,
-        scene: &mut Scene<Texture>,
-        tex: &Rc<Texture>,
-        game_objects: &mut SlotMap<GameObjectKey, GameObject>
-        // TODO: parameterize
+        sim: &mut Simulation,
+        player: &PlayerDef
     ) -> GameObjectKey {
-    let mut sprite = Sprite::from_texture(tex.clone());
-    let ship_id = scene.add_child(sprite);
-    scene.run(ship_id, &Action(ScaleBy(0., -0.5, -0.5)));
-
-    let mut def = b2::BodyDef {
+    let def = b2::BodyDef {
         body_type: b2::BodyType::Dynamic,
-        position: b2::Vec2 { x: 50., y: -50. },
+        position: b2::Vec2 { x: player.spawn[0], y: player.spawn[1] },
         .. b2::BodyDef::new()
     };
 
-    let ship_handle = world.create_body(&def);
+    let ship_handle = sim.world.create_body(&def);
     {
-        let mut body = world.body_mut(ship_handle);
+        let mut body = sim.world.body_mut(ship_handle);
         body.set_gravity_scale(0.);
-        body.set_linear_damping(2.);
+        body.set_linear_damping(player.linear_damping);
         body.set_rotation_fixed(true);
 
 
         let mut shape = b2::CircleShape::new();
-        shape.set_radius(3.6);
+        shape.set_radius(player.scale as f32 * SHIP_RADIUS);
 
         let mut fixture_def = b2::FixtureDef::new();
-        fixture_def.density = 2.;
-        fixture_def.restitution = 0.5;
+        fixture_def.density = player.density;
+        fixture_def.restitution = player.restitution;
         let handle = body.create_fixture(&shape, &mut fixture_def);
     }
 
-    game_objects.insert(GameObject::new(ship_handle, ship_id, GameObjectType::Player))
+    let appearance = Appearance{texture: sim.texture_id(&player.texture), scale: player.scale};
+    sim.insert_object(GameObject {
+        health: Some(player.health),
+        // the ship itself is harmless, its weapons do the damage
+        damage: 0.,
+        .. GameObject::new(ship_handle, appearance, GameObjectType::Player{max_force: player.max_force})
+    }, ObjectRole::Player)
+}
+
+/// Places the level's `index`th pickup as a static sensor that doesn't get in anything's way.
+pub fn make_pickup(sim: &mut Simulation, def: &PickupDef, index: usize) -> GameObjectKey {
+    let body_def = b2::BodyDef {
+        body_type: b2::BodyType::Static,
+        position: b2::Vec2 { x: def.x, y: def.y },
+        .. b2::BodyDef::new()
+    };
+
+    let pickup_handle = sim.world.create_body(&body_def);
+    {
+        let mut body = sim.world.body_mut(pickup_handle);
+        let mut shape = b2::CircleShape::new();
+        shape.set_radius(PICKUP_RADIUS);
+
+        let mut fixture_def = b2::FixtureDef::new();
+        fixture_def.is_sensor = true;
+        body.create_fixture(&shape, &mut fixture_def);
+    }
+
+    let scale = (PICKUP_RADIUS / SHIP_RADIUS) as f64;
+    let appearance = Appearance{texture: sim.texture_id(&def.texture), scale};
+    sim.insert_object(GameObject::new(pickup_handle, appearance, GameObjectType::Pickup{index}), ObjectRole::Pickup)
+}
+
+/// Creates the walls around the arena and its obstacles as one static body.
+pub fn make_arena(world: &mut b2::World::<GameUserData>, def: &ArenaDef, filter: &b2::Filter) -> TypedHandle<b2::Body> {
+    let walls_def = b2::BodyDef {
+        body_type: b2::BodyType::Static,
+        .. b2::BodyDef::new()
+    };
+
+    let walls_handle = world.create_body(&walls_def);
+    {
+        let mut body = world.body_mut(walls_handle);
+        let mut fixture_def = b2::FixtureDef::new();
+        fixture_def.filter = filter.clone();
+
+        let (min, max) = def.bounds();
+        let edges = [
+            (b2::Vec2{x:min.x, y:max.y}, b2::Vec2{x:min.x, y:min.y}),
+            (b2::Vec2{x:min.x, y:min.y}, b2::Vec2{x:max.x, y:min.y}),
+            (b2::Vec2{x:max.x, y:max.y}, b2::Vec2{x:max.x, y:min.y}),
+            (b2::Vec2{x:min.x, y:max.y}, b2::Vec2{x:max.x, y:max.y}),
+        ];
+        fixture_def.restitution = def.restitution;
+        fixture_def.friction = def.friction;
+        for &(v1, v2) in &edges {
+            let shape = b2::EdgeShape::new_with(&v1, &v2);
+            body.create_fixture(&shape, &mut fixture_def);
+        }
+
+        for obstacle in &def.obstacles {
+            fixture_def.restitution = obstacle.restitution.unwrap_or(def.restitution);
+            fixture_def.friction = obstacle.friction.unwrap_or(def.friction);
+            match obstacle.shape {
+                ShapeDef::Edge{from, to} => {
+                    let shape = b2::EdgeShape::new_with(&vec2(from), &vec2(to));
+                    body.create_fixture(&shape, &mut fixture_def);
+                },
+                ShapeDef::Box{x, y, width, height, angle} => {
+                    let shape = b2::PolygonShape::new_oriented_box(width / 2., height / 2., &b2::Vec2{x, y}, angle);
+                    body.create_fixture(&shape, &mut fixture_def);
+                },
+                ShapeDef::Polygon{ref points} => {
+                    let points: Vec<b2::Vec2> = points.iter().map(|&p| vec2(p)).collect();
+                    let shape = b2::PolygonShape::new_with(&points);
+                    body.create_fixture(&shape, &mut fixture_def);
+                },
+                ShapeDef::Circle{x, y, radius} => {
+                    let shape = b2::CircleShape::new_with(b2::Vec2{x, y}, radius);
+                    body.create_fixture(&shape, &mut fixture_def);
+                },
+                ShapeDef::Chain{ref points, closed} => {
+                    let points: Vec<b2::Vec2> = points.iter().map(|&p| vec2(p)).collect();
+                    let shape = if closed {
+                        b2::ChainShape::new_loop(&points)
+                    } else {
+                        b2::ChainShape::new_chain(&points)
+                    };
+                    body.create_fixture(&shape, &mut fixture_def);
+                },
+            }
+        }
+    }
+    walls_handle
+}
+
+fn vec2(point: [f32; 2]) -> b2::Vec2 {
+    b2::Vec2{x: point[0], y: point[1]}
 }
 
 pub fn make_chaser(
-        game: &mut Game,
-        tex: &Rc<Texture>,
-        def: ChaserDef,
+        sim: &mut Simulation,
+        def: &ChaserDef,
         props: &ChaserProps) -> GameObjectKey
 {
     let mut def = b2::BodyDef {
@@ -77,15 +157,15 @@ pub fn make_chaser(
         .. b2::BodyDef::new()
     };
 
-    let ball_handle = game.world.create_body(&def);
+    let ball_handle = sim.world.create_body(&def);
     {
-        let mut body = game.world.body_mut(ball_handle);
+        let mut body = sim.world.body_mut(ball_handle);
         body.set_gravity_scale(0.);
         body.set_linear_damping(props.linear_damping);
         body.set_rotation_fixed(true);
 
         let mut shape = b2::CircleShape::new();
-        shape.set_radius(props.scale as f32 * 7.2);
+        shape.set_radius(props.scale as f32 * SHIP_RADIUS);
 
         let mut fixture_def = b2::FixtureDef::new();
         fixture_def.density = props.density;
@@ -93,124 +173,267 @@ pub fn make_chaser(
         let handle = body.create_fixture(&shape, &mut fixture_def);
     }
 
-    let ball_id;
-    let mut sprite = Sprite::from_texture(tex.clone());
-    ball_id = game.scene.add_child(sprite);
-    game.scene.run(ball_id, &Action(ScaleBy(0., draw_scale(props.scale), draw_scale(props.scale))));
-
-    game.objects.insert(GameObject::new(ball_handle, ball_id, GameObjectType::Chaser))
+    let appearance = Appearance{texture: sim.texture_id(&props.texture), scale: props.scale};
+    let key = sim.insert_object(GameObject {
+        health: Some(props.health),
+        damage: props.damage,
+        .. GameObject::new(ball_handle, appearance, GameObjectType::Chaser)
+    }, ObjectRole::Chaser);
+    let behavior = match props.ai {
+        Some(ref ai) => ai.0.clone(),
+        None => props.behavior.tree(props.aggression),
+    };
+    sim.brains.insert(key, Brain::new(behavior, props.chase_force));
+    key
 }
 
 pub fn make_ball(
-        game: &mut Game,
-        tex: &Rc<Texture>,
+        sim: &mut Simulation,
         def: &ColliderDef,
         props: &ColliderProps,
-        root_pos: b2::Vec2
+        root_pos: b2::Vec2,
+        part: WeaponPart
     ) -> GameObjectKey
 {
-    let mut def = b2::BodyDef {
+    let mut body_def = b2::BodyDef {
         body_type: b2::BodyType::Dynamic,
         position: b2::Vec2 { x: def.x + root_pos.x, y: def.y + root_pos.y },
         .. b2::BodyDef::new()
     };
 
-    let mut sprite = Sprite::from_texture(tex.clone());
-    let whip_id = game.scene.add_child(sprite);
-    game.scene.run(whip_id, &Action(ScaleBy(0., draw_scale(props.scale), draw_scale(props.scale))));
-
-    let whip_handle = game.world.create_body(&def);
+    let whip_handle = sim.world.create_body(&body_def);
     {
-        let mut body = game.world.body_mut(whip_handle);
+        let mut body = sim.world.body_mut(whip_handle);
         body.set_linear_damping(props.linear_damping);
         body.set_angular_damping(props.angular_damping);
 
-        let mut shape = b2::CircleShape::new();
-        shape.set_radius(1.8);
-
         let mut fixture_def = b2::FixtureDef::new();
         fixture_def.density = props.density;
         fixture_def.restitution = props.restitution;
-        let handle = body.create_fixture(&shape, &mut fixture_def);
+        add_collider_shape(&mut body, &def.shape, props, &mut fixture_def);
     }
 
-    game.objects.insert(GameObject::new(whip_handle, whip_id, GameObjectType::Default))
+    // the sprite covers the whole shape
+    let scale = (def.shape.reach(props) / SHIP_RADIUS) as f64;
+    let appearance = Appearance{texture: sim.texture_id(DEFAULT_TEXTURE), scale};
+    sim.insert_object(GameObject {
+        damage: props.damage,
+        weapon: Some(part),
+        .. GameObject::new(whip_handle, appearance, GameObjectType::Default)
+    }, ObjectRole::WeaponCollider)
+}
+
+fn add_collider_shape(
+        body: &mut b2::MetaBody<GameUserData>,
+        shape: &ColliderShape,
+        props: &ColliderProps,
+        fixture_def: &mut b2::FixtureDef) {
+    match *shape {
+        ColliderShape::Circle{x, y, radius} => {
+            let shape = b2::CircleShape::new_with(b2::Vec2{x, y}, radius.unwrap_or_else(|| props.radius()));
+            body.create_fixture(&shape, fixture_def);
+        },
+        ColliderShape::Box{x, y, width, height, angle} => {
+            let shape = b2::PolygonShape::new_oriented_box(width / 2., height / 2., &b2::Vec2{x, y}, angle);
+            body.create_fixture(&shape, fixture_def);
+        },
+        ColliderShape::Polygon{ref points} => {
+            let points: Vec<b2::Vec2> = points.iter().map(|&p| vec2(p)).collect();
+            let shape = b2::PolygonShape::new_with(&points);
+            body.create_fixture(&shape, fixture_def);
+        },
+        ColliderShape::Compound{ref parts} => {
+            for part in parts {
+                add_collider_shape(body, part, props, fixture_def);
+            }
+        },
+    }
 }
 
 pub fn make_rope_joint(
-        game: &mut Game,
+        sim: &mut Simulation,
         handle1: GameObjectKey,
         handle2: GameObjectKey,
         length: f32) -> Option<TypedHandle<b2::Joint>>{
     let mut rope_joint_def = b2::RopeJointDef::new(
-        game.objects.get(handle1)?.physics_handle,
-        game.objects.get(handle2)?.physics_handle);
+        sim.objects.get(handle1)?.physics_handle,
+        sim.objects.get(handle2)?.physics_handle);
     rope_joint_def.collide_connected = false;
     rope_joint_def.max_length = length;
-    Some(game.world.create_joint(&rope_joint_def))
+    Some(sim.world.create_joint(&rope_joint_def))
 }
 
 pub fn make_chain(
-        game: &mut Game,
+        sim: &mut Simulation,
         key1: GameObjectKey,
         key2: GameObjectKey,
-        tex: &Rc<Texture>,
         def: &ChainDef,
-        root_pos: b2::Vec2) -> Option<TypedHandle<b2::Joint>> {
+        root_pos: b2::Vec2,
+        part: WeaponPart) -> Option<TypedHandle<b2::Joint>> {
 
-    let center1 = game.body(key1)?.local_center().clone();
-    let handle_prev = game.handle(key1)?;
-    let mut link_prev = make_chain_link(game, handle_prev, tex.clone(), def.x + root_pos.x, def.y + root_pos.y, center1);
+    let center1 = sim.body(key1)?.local_center().clone();
+    let handle_prev = sim.handle(key1)?;
+    let link = GameObjectType::ChainLink{from: key1, to: key2, break_force: def.break_force};
+    let mut link_prev = make_chain_link(sim, handle_prev, def, root_pos, center1, part, link);
+    let link_anchor = b2::Vec2{x: def.link_size / 2., y: def.link_size / 2.};
 
     for i in 0 .. def.length {
-        let handle_prev = game.handle(link_prev)?;
-        link_prev = make_chain_link(game, handle_prev, tex.clone(), def.x + root_pos.x, def.y + root_pos.y, b2::Vec2{x: 0.18, y: 0.18});
+        let handle_prev = sim.handle(link_prev)?;
+        link_prev = make_chain_link(sim, handle_prev, def, root_pos, link_anchor, part, link);
     }
 
-    let mut rev_def = b2::RopeJointDef::new(game.handle(link_prev)?, game.handle(key2)?);
+    let mut rev_def = b2::RopeJointDef::new(sim.handle(link_prev)?, sim.handle(key2)?);
     rev_def.collide_connected = false;
-    rev_def.local_anchor_a = b2::Vec2{x: 0.18, y: 0.18};
-    rev_def.local_anchor_b = game.body(key2)?.local_center().clone();
-    rev_def.max_length = 0.3;
-    game.world.create_joint(&rev_def);
-    make_rope_joint(game, key1, key2, def.length as f32)
+    rev_def.local_anchor_a = link_anchor;
+    rev_def.local_anchor_b = sim.body(key2)?.local_center().clone();
+    rev_def.max_length = def.final_length;
+    sim.world.create_joint(&rev_def);
+    // one spacing per link, which at the default spacing is the chain length weapons were tuned with
+    let max_length = def.length as f32 * def.link_spacing;
+    make_rope_joint(sim, key1, key2, max_length)
+}
+
+/// Joins two objects of a weapon whose root is at `root_pos`.
+pub fn make_joint(
+        sim: &mut Simulation,
+        key1: GameObjectKey,
+        key2: GameObjectKey,
+        def: &JointDef,
+        root_pos: b2::Vec2) -> Option<b2::JointHandle> {
+    let (handle1, handle2) = (sim.handle(key1)?, sim.handle(key2)?);
+    let at = |[x, y]: [f32; 2]| b2::Vec2{x: root_pos.x + x, y: root_pos.y + y};
+    let center1 = *sim.body(key1)?.world_center();
+    let center2 = *sim.body(key2)?.world_center();
+
+    let joint = match def.joint {
+        JointKind::Weld{anchor, frequency, damping} => {
+            let mut joint_def = b2::WeldJointDef::new(handle1, handle2);
+            joint_def.init(&sim.world, handle1, handle2, &at(anchor));
+            joint_def.collide_connected = def.collide;
+            joint_def.frequency = frequency;
+            joint_def.damping_ratio = damping;
+            sim.world.create_joint(&joint_def)
+        },
+        JointKind::Rod => {
+            let mut joint_def = b2::DistanceJointDef::new(handle1, handle2);
+            joint_def.init(&sim.world, handle1, handle2, &center1, &center2);
+            joint_def.collide_connected = def.collide;
+            sim.world.create_joint(&joint_def)
+        },
+        JointKind::Spring{frequency, damping, length} => {
+            let mut joint_def = b2::DistanceJointDef::new(handle1, handle2);
+            joint_def.init(&sim.world, handle1, handle2, &center1, &center2);
+            joint_def.collide_connected = def.collide;
+            joint_def.frequency = frequency;
+            joint_def.damping_ratio = damping;
+            if let Some(length) = length {
+                joint_def.length = length;
+            }
+            sim.world.create_joint(&joint_def)
+        },
+        JointKind::Revolute{anchor, limits, motor_speed, max_motor_torque} => {
+            let mut joint_def = b2::RevoluteJointDef::new(handle1, handle2);
+            joint_def.init(&sim.world, handle1, handle2, &at(anchor));
+            joint_def.collide_connected = def.collide;
+            if let Some([lower, upper]) = limits {
+                joint_def.enable_limit = true;
+                joint_def.lower_angle = lower;
+                joint_def.upper_angle = upper;
+            }
+            joint_def.enable_motor = max_motor_torque > 0.;
+            joint_def.motor_speed = motor_speed;
+            joint_def.max_motor_torque = max_motor_torque;
+            sim.world.create_joint(&joint_def)
+        },
+        JointKind::Prismatic{anchor, axis, limits, motor_speed, max_motor_force} => {
+            let mut joint_def = b2::PrismaticJointDef::new(handle1, handle2);
+            joint_def.init(&sim.world, handle1, handle2, &at(anchor), &b2::Vec2{x: axis[0], y: axis[1]});
+            joint_def.collide_connected = def.collide;
+            if let Some([lower, upper]) = limits {
+                joint_def.enable_limit = true;
+                joint_def.lower_translation = lower;
+                joint_def.upper_translation = upper;
+            }
+            joint_def.enable_motor = max_motor_force > 0.;
+            joint_def.motor_speed = motor_speed;
+            joint_def.max_motor_force = max_motor_force;
+            sim.world.create_joint(&joint_def)
+        },
+        JointKind::Pulley{ground1, ground2, ratio} => {
+            let mut joint_def = b2::PulleyJointDef::new(handle1, handle2);
+            joint_def.init(handle1, handle2, at(ground1), at(ground2), &center1, &center2, ratio);
+            // init leaves the anchors where PulleyJointDef::new put them
+            joint_def.local_anchor_a = *sim.body(key1)?.local_center();
+            joint_def.local_anchor_b = *sim.body(key2)?.local_center();
+            joint_def.collide_connected = def.collide;
+            sim.world.create_joint(&joint_def)
+        },
+    };
+    Some(joint)
 }
 
 fn make_chain_link(
-        game: &mut Game,
+        sim: &mut Simulation,
         handle_prev: TypedHandle<b2::Body>,
-        tex: Rc<Texture>,
-        x: f32,
-        y: f32,
-        local_anchor_prev: b2::Vec2) -> GameObjectKey {
-    let mut sprite = Sprite::from_texture(tex);
-    let link_id = game.scene.add_child(sprite);
-    game.scene.run(link_id, &Action(ScaleBy(0., -0.92, -0.92)));
+        chain: &ChainDef,
+        root_pos: b2::Vec2,
+        local_anchor_prev: b2::Vec2,
+        part: WeaponPart,
+        link: GameObjectType) -> GameObjectKey {
     let mut def = b2::BodyDef {
         body_type: b2::BodyType::Dynamic,
-        position: b2::Vec2 { x, y },
+        position: b2::Vec2 { x: chain.x + root_pos.x, y: chain.y + root_pos.y },
         fixed_rotation: false,
         .. b2::BodyDef::new()
     };
 
-    let link_handle = game.world.create_body(&def);
+    let link_handle = sim.world.create_body(&def);
     {
-        let mut body = game.world.body_mut(link_handle);
+        let mut body = sim.world.body_mut(link_handle);
         body.set_rotation_fixed(false);
 
-        let shape = b2::PolygonShape::new_box(0.36,0.36);
+        let shape = b2::PolygonShape::new_box(chain.link_size, chain.link_size);
 
-        let handle = body.create_fast_fixture(&shape, 0.01);
-        let mut fixture = body.fixture_mut(handle);
-        fixture.set_filter_data(&b2::Filter{category_bits: 0, mask_bits: 0, group_index: 0});
+        let handle = body.create_fast_fixture(&shape, chain.link_density);
+        if !chain.collide {
+            let mut fixture = body.fixture_mut(handle);
+            fixture.set_filter_data(&b2::Filter{category_bits: 0, mask_bits: 0, group_index: 0});
+        }
     }
 
     let mut rev_def = b2::RopeJointDef::new(handle_prev, link_handle);
     rev_def.collide_connected = false;
     rev_def.local_anchor_a = local_anchor_prev;
-    rev_def.local_anchor_b = b2::Vec2{x: 0.18, y: 0.18};
-    rev_def.max_length = 1.0;
-    game.world.create_joint(&rev_def);
+    rev_def.local_anchor_b = b2::Vec2{x: chain.link_size / 2., y: chain.link_size / 2.};
+    rev_def.max_length = chain.link_spacing;
+    sim.world.create_joint(&rev_def);
+
+    let scale = LINK_SCALE * chain.link_size as f64;
+    let appearance = Appearance{texture: sim.texture_id(&chain.link_texture), scale};
+    sim.insert_object(GameObject {
+        weapon: Some(part),
+        .. GameObject::new(link_handle, appearance, link)
+    }, ObjectRole::ChainLink)
+}
 
-    game.objects.insert(GameObject::new(link_handle, link_id, GameObjectType::Default))
+#[cfg(test)]
+mod tests {
+    use swingyships::testing::build_level;
+    use wrapped2d::b2;
+
+    #[test]
+    fn chain_tethers_are_as_long_as_their_chains() {
+        // noob_whip is 10 links long, rebound and deadblow 15, all at the default spacing
+        let sim = build_level("level.toml");
+        let mut tethers: Vec<f32> = sim.world.joints()
+            .filter_map(|(_, joint)| match **joint.borrow() {
+                b2::UnknownJoint::Rope(ref rope) => Some(rope.max_length()),
+                _ => None,
+            })
+            // leaving out the short ropes from each chain's last link to its end
+            .filter(|&length| length > 1.)
+            .collect();
+        tethers.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(tethers, vec![10., 15., 15.]);
+    }
 }
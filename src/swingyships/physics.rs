@@ -1,30 +1,239 @@
-use b2::{ContactListener, Vec2};
+use wrapped2d::b2::{ContactListener, Filter, UnknownJoint, Vec2};
 use wrapped2d::collision::Manifold;
 use wrapped2d::dynamics::world::callbacks::{ContactImpulse, ContactAccess};
+use wrapped2d::user_data::{UserData, UserDataTypes};
+use wrapped2d::wrap::WrappedBase;
+use swingyships::game::GameObjectKey;
+use slotmap::KeyData;
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::os::raw::c_void;
 use std::rc::Rc;
 
+/// The user data attached to bodies, fixtures and joints in the game world. It has to be
+/// serializable for the world to go into a snapshot.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GameUserData;
+
+impl UserDataTypes for GameUserData {
+    type BodyData = ObjectData;
+    type JointData = ();
+    type FixtureData = ObjectData;
+}
+
+/// What part an object's body plays in the game.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ObjectRole {
+    /// Walls and anything else that isn't a game object.
+    Scenery,
+    Player,
+    Chaser,
+    WeaponCollider,
+    ChainLink,
+    /// A weapon lying in the arena, sensing the ships that touch it.
+    Pickup,
+}
+
+/// A group of fixtures that collision masks pick out, like every chaser or every weapon the
+/// player carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CollisionLayer {
+    Player,
+    PlayerWeapon,
+    Enemy,
+    EnemyWeapon,
+    /// The arena's walls and obstacles.
+    Wall,
+    /// Bits of weapons that don't belong to anyone any more.
+    Debris,
+}
+
+pub const COLLISION_LAYERS: [CollisionLayer; 6] = [
+    CollisionLayer::Player,
+    CollisionLayer::PlayerWeapon,
+    CollisionLayer::Enemy,
+    CollisionLayer::EnemyWeapon,
+    CollisionLayer::Wall,
+    CollisionLayer::Debris,
+];
+
+/// The Box2D filter of every collision layer.
+#[derive(Debug, Clone, Copy)]
+pub struct CollisionFilters {
+    masks: [u16; 6],
+    /// Whether a ship's weapons can hit the ship itself.
+    hit_owner: bool
+}
+
+impl CollisionLayer {
+    fn bit(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+impl Default for CollisionFilters {
+    /// Everything hits everything.
+    fn default() -> CollisionFilters {
+        CollisionFilters::new(|_, _| true, true)
+    }
+}
+
+impl CollisionFilters {
+    /// Filters where `a` hits `b` if `hits(a, b)`, which should be the same both ways round.
+    pub fn new<F: Fn(CollisionLayer, CollisionLayer) -> bool>(hits: F, hit_owner: bool) -> CollisionFilters {
+        let mut masks = [0; 6];
+        for &a in &COLLISION_LAYERS {
+            for &b in &COLLISION_LAYERS {
+                if hits(a, b) {
+                    masks[a as usize] |= b.bit();
+                }
+            }
+        }
+        CollisionFilters{masks, hit_owner}
+    }
+
+    /// The filter for a fixture on `layer` belonging to the ship `owner`, or to no one.
+    pub fn filter(&self, layer: CollisionLayer, owner: Option<GameObjectKey>) -> Filter {
+        // fixtures in the same negative group never collide
+        let group_index = match owner {
+            Some(owner) if !self.hit_owner => -1 - (KeyData::from(owner).as_ffi() as u32 % 0x7fff) as i16,
+            _ => 0,
+        };
+        Filter{category_bits: layer.bit(), mask_bits: self.masks[layer as usize], group_index}
+    }
+}
+
+/// Which game object a body or fixture belongs to. Scenery has the null key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectData {
+    pub key: GameObjectKey,
+    pub role: ObjectRole
+}
+
+impl Default for ObjectData {
+    fn default() -> ObjectData {
+        ObjectData{key: GameObjectKey::default(), role: ObjectRole::Scenery}
+    }
+}
+
+/// Contacts with a smaller total impulse than this, like bodies resting against each other,
+/// aren't reported as impacts.
+pub const IMPACT_IMPULSE_THRESHOLD: f32 = 100.;
+
+/// Something that happened between two bodies during a world step.
+// not every field has a subscriber yet
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub enum CollisionEvent {
+    ContactBegan {
+        a: ObjectData,
+        b: ObjectData
+    },
+    ContactEnded {
+        a: ObjectData,
+        b: ObjectData
+    },
+    /// A chain pulled harder than its break force and snapped at `point`, letting go of `to`.
+    ChainBroke {
+        from: ObjectData,
+        to: ObjectData,
+        point: Vec2
+    },
+    /// A contact that took at least `IMPACT_IMPULSE_THRESHOLD` to resolve.
+    Impact {
+        a: ObjectData,
+        b: ObjectData,
+        point: Vec2,
+        /// Points from `a` to `b`.
+        normal: Vec2,
+        /// Total normal impulse over every contact point.
+        impulse: f32,
+        /// Velocity of `b` relative to `a` at `point` just before the impact.
+        relative_velocity: Vec2
+    },
+}
+
+extern "C" {
+    // wrapped2d 0.4 binds this without its `inv_dt` argument, so its `reaction_force` returns
+    // whatever happens to be in the register
+    fn Joint_get_reaction_force_virtual(joint: *const c_void, inv_dt: f32) -> Vec2;
+}
+
+/// The force `joint` held its bodies together with during the last step of `dt` seconds.
+pub fn reaction_force(joint: &UnknownJoint, dt: f32) -> Vec2 {
+    unsafe { Joint_get_reaction_force_virtual(joint.base_ptr() as *const c_void, 1. / dt) }
+}
+
+/// Events queued by the contact listener during a step, in the order Box2D reported them.
+pub type CollisionEvents = Rc<RefCell<Vec<CollisionEvent>>>;
+
 pub struct FixRestitutionListener {
-    pub big_impacts: Rc<RefCell<Vec<(Vec2, f32)>>>
+    pub events: CollisionEvents,
+    /// Relative velocities from `pre_solve`, keyed by body indices, waiting for `post_solve`.
+    approach_velocities: HashMap<(usize, usize), Vec2>
 }
 
-impl<U: wrapped2d::user_data::UserDataTypes> ContactListener<U> for FixRestitutionListener {
-    fn begin_contact(&mut self, access:ContactAccess<U>) {
-        access.contact.set_restitution(access.fixture_a.restitution() * access.fixture_b.restitution())
+impl FixRestitutionListener {
+    pub fn new(events: CollisionEvents) -> FixRestitutionListener {
+        FixRestitutionListener{events, approach_velocities: HashMap::new()}
     }
+}
 
-    fn end_contact(&mut self, access:ContactAccess<U>) {
+fn body_pair(access: &ContactAccess<GameUserData>) -> (usize, usize) {
+    (access.body_a.handle().index(), access.body_b.handle().index())
+}
 
+/// The middle of the contact points, which Box2D only fills in up to the manifold's count.
+fn contact_point(access: &ContactAccess<GameUserData>) -> (Vec2, Vec2) {
+    let w_manifold = access.contact.world_manifold();
+    let count = access.contact.manifold().count.max(1) as usize;
+    let mut point = Vec2{x: 0., y: 0.};
+    for p in &w_manifold.points[.. count] {
+        point = point + *p;
     }
+    (point / count as f32, w_manifold.normal)
+}
 
-    fn pre_solve(&mut self, access:ContactAccess<U>, _: &Manifold) {
+impl ContactListener<GameUserData> for FixRestitutionListener {
+    fn begin_contact(&mut self, access:ContactAccess<GameUserData>) {
+        access.contact.set_restitution(access.fixture_a.restitution() * access.fixture_b.restitution());
+        self.events.borrow_mut().push(CollisionEvent::ContactBegan {
+            a: *access.body_a.user_data(),
+            b: *access.body_b.user_data()
+        });
+    }
+
+    fn end_contact(&mut self, access:ContactAccess<GameUserData>) {
+        self.approach_velocities.remove(&body_pair(&access));
+        self.events.borrow_mut().push(CollisionEvent::ContactEnded {
+            a: *access.body_a.user_data(),
+            b: *access.body_b.user_data()
+        });
+    }
 
+    fn pre_solve(&mut self, access:ContactAccess<GameUserData>, _: &Manifold) {
+        let (point, _) = contact_point(&access);
+        let relative_velocity = access.body_b.linear_velocity_from_world_point(&point)
+            - access.body_a.linear_velocity_from_world_point(&point);
+        self.approach_velocities.insert(body_pair(&access), relative_velocity);
     }
 
-    fn post_solve(&mut self, access:ContactAccess<U>, impulse: &ContactImpulse) {
-        if impulse.normal_impulses[0] > 500. {
-            let w_manifold = access.contact.world_manifold();
-            self.big_impacts.borrow_mut().push((w_manifold.points[0] + w_manifold.points[1] / 2., impulse.normal_impulses[0]));
+    fn post_solve(&mut self, access:ContactAccess<GameUserData>, impulse: &ContactImpulse) {
+        let total: f32 = impulse.normal_impulses[.. impulse.count as usize].iter().sum();
+        if total > IMPACT_IMPULSE_THRESHOLD {
+            let (point, normal) = contact_point(&access);
+            let relative_velocity = self.approach_velocities.get(&body_pair(&access))
+                .cloned()
+                .unwrap_or(Vec2{x: 0., y: 0.});
+            self.events.borrow_mut().push(CollisionEvent::Impact {
+                a: *access.body_a.user_data(),
+                b: *access.body_b.user_data(),
+                point,
+                normal,
+                impulse: total,
+                relative_velocity
+            });
         }
     }
 }
@@ -0,0 +1,167 @@
+extern crate toml;
+
+use swingyships::level_loader::{Sources, SourceFile};
+use swingyships::load_error::LoadError;
+use swingyships::simulation::{Simulation, Input};
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// A recorded play session: the files the level was built from and the player's input on
+/// every tick that had any.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Replay {
+    pub tick_rate: f64,
+    #[serde(default)]
+    pub seed: u32,
+    pub ticks: u64,
+    /// `Simulation::state_hash` after the last tick, used to check that playback didn't diverge.
+    pub final_state: Option<String>,
+    pub sources: Sources,
+    #[serde(default)]
+    pub inputs: Vec<RecordedInput>
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct RecordedInput {
+    pub tick: u64,
+    pub mouse_relative: [f64; 2],
+    pub toggle_capture: bool,
+    #[serde(default)]
+    pub cycle_weapon: bool,
+    #[serde(default)]
+    pub drop_weapon: bool,
+    #[serde(default)]
+    pub pick_up_weapon: bool
+}
+
+impl Replay {
+    pub fn new(sources: Sources, tick_rate: f64, seed: u32) -> Replay {
+        Replay {
+            tick_rate,
+            seed,
+            ticks: 0,
+            final_state: None,
+            sources,
+            inputs: Vec::new()
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Replay, Vec<LoadError>> {
+        let file = SourceFile::read(path).map_err(|e| vec![e])?;
+        toml::from_str(&file.contents).map_err(|e| vec![LoadError::parse(&file, e)])
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let contents = toml::to_string(self).expect("could not serialize replay");
+        fs::write(path, contents)
+    }
+
+    /// Records the input for `tick`. Ticks must be recorded in order.
+    pub fn record(&mut self, tick: u64, input: &Input) {
+        self.ticks = tick + 1;
+        let weapon_action = input.cycle_weapon || input.drop_weapon || input.pick_up_weapon;
+        if input.mouse_relative != [0., 0.] || input.toggle_capture || weapon_action {
+            self.inputs.push(RecordedInput {
+                tick,
+                mouse_relative: input.mouse_relative,
+                toggle_capture: input.toggle_capture,
+                cycle_weapon: input.cycle_weapon,
+                drop_weapon: input.drop_weapon,
+                pick_up_weapon: input.pick_up_weapon
+            });
+        }
+    }
+
+    /// Marks the recording as finished at the simulation's current state.
+    pub fn finish(&mut self, sim: &Simulation) {
+        self.ticks = sim.tick;
+        self.final_state = Some(format!("{:016x}", sim.state_hash()));
+    }
+
+    pub fn input(&self, tick: u64) -> Input {
+        match self.inputs.binary_search_by_key(&tick, |i| i.tick) {
+            Ok(i) => Input {
+                mouse_relative: self.inputs[i].mouse_relative,
+                toggle_capture: self.inputs[i].toggle_capture,
+                cycle_weapon: self.inputs[i].cycle_weapon,
+                drop_weapon: self.inputs[i].drop_weapon,
+                pick_up_weapon: self.inputs[i].pick_up_weapon
+            },
+            Err(_) => Input::default()
+        }
+    }
+
+    /// Builds the recorded level from scratch, ready to be played back from tick 0.
+    pub fn simulation(&self) -> Result<Simulation, Vec<LoadError>> {
+        self.sources.build(self.tick_rate, self.seed)
+    }
+
+    /// Plays the whole replay without rendering and returns the final state.
+    pub fn run(&self) -> Result<Simulation, Vec<LoadError>> {
+        let mut sim = self.simulation()?;
+        while sim.tick < self.ticks {
+            let input = self.input(sim.tick);
+            sim.step(&input);
+        }
+        Ok(sim)
+    }
+
+    /// Whether `sim` ended up in the state that was recorded, if a final state was recorded.
+    pub fn matches(&self, sim: &Simulation) -> Option<bool> {
+        let expected = self.final_state.as_ref()?;
+        Some(*expected == format!("{:016x}", sim.state_hash()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use swingyships::load_error::LoadError;
+    use swingyships::simulation::Input;
+    use swingyships::testing::{read_level, temp_file};
+    use super::Replay;
+
+    use std::fs;
+
+    #[test]
+    fn loaded_replay_plays_back_to_the_recorded_state() {
+        let mut replay = Replay::new(read_level("level_waves.toml"), 60., 7);
+        let mut sim = replay.simulation().expect("could not build the level");
+        while sim.tick < 600 {
+            let input = Input {
+                mouse_relative: [(sim.tick as f64 * 0.05).cos() * 20., (sim.tick as f64 * 0.03).sin() * 20.],
+                cycle_weapon: [100, 250, 400].contains(&sim.tick),
+                ..Input::default()
+            };
+            replay.record(sim.tick, &input);
+            sim.step(&input);
+        }
+        replay.finish(&sim);
+
+        let path = temp_file("replay.toml");
+        replay.save(&path).expect("could not save the replay");
+        let loaded = Replay::load(&path);
+        fs::remove_file(&path).expect("could not remove the replay");
+        let loaded = loaded.expect("could not load the replay");
+
+        assert_eq!(loaded.matches(&loaded.run().expect("could not build the level")), Some(true));
+    }
+
+    #[test]
+    fn unreadable_replays_are_load_errors() {
+        match Replay::load(&temp_file("missing_replay.toml")).map_err(|errors| errors.into_iter().next()) {
+            Err(Some(LoadError::Io{..})) => (),
+            other => panic!("expected an Io error, got {:?}", other),
+        }
+
+        let path = temp_file("bad_replay.toml");
+        fs::write(&path, "tick_rate = 60.0\nticks =\n").expect("could not write the replay");
+        let loaded = Replay::load(&path);
+        fs::remove_file(&path).expect("could not remove the replay");
+        match loaded.map_err(|errors| errors.into_iter().next()) {
+            Err(Some(LoadError::Parse{at, ..})) => assert_eq!(at.position.map(|(line, _)| line), Some(2)),
+            other => panic!("expected a Parse error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,1126 @@
+extern crate wrapped2d;
+extern crate slotmap;
+
+use swingyships::game::{GameObject, GameObjectType, GameObjectKey, TextureId, WeaponId};
+use swingyships::physics::{FixRestitutionListener, GameUserData, CollisionEvent, CollisionEvents, CollisionFilters, CollisionLayer, ObjectData, ObjectRole, reaction_force};
+use swingyships::snapshot::SimulationSnapshot;
+use swingyships::level_loader::{DamageDef, Definitions, Sources, WaveTrigger, load_weapon, spawn_wave};
+use swingyships::load_error::LoadError;
+use swingyships::ai::Brain;
+
+use wrapped2d::b2;
+use wrapped2d::b2::Joint;
+use wrapped2d::handle::TypedHandle;
+use wrapped2d::user_data::UserData;
+use wrapped2d::serialize::{BodyId, IdToHandle, WorldSnapshot};
+
+use slotmap::{SlotMap, SecondaryMap};
+
+use std::cell::{Ref, RefCell};
+use std::fmt;
+use std::hash::Hasher;
+use std::mem;
+use std::rc::Rc;
+
+pub const DEFAULT_TICK_RATE: f64 = 60.;
+pub const DEFAULT_MAX_CATCH_UP: u32 = 5;
+/// Hit points taken per unit of contact impulse, before the attacker's damage multiplier.
+pub const DAMAGE_PER_IMPULSE: f32 = 0.01;
+/// Furthest a dropped weapon's collider can be from the player to be picked up.
+pub const PICK_UP_DISTANCE: f32 = 15.;
+
+/// Everything the player did since the last tick.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Input {
+    pub mouse_relative: [f64; 2],
+    pub toggle_capture: bool,
+    pub cycle_weapon: bool,
+    pub drop_weapon: bool,
+    pub pick_up_weapon: bool,
+}
+
+impl Input {
+    /// The part of a frame's input that tick `i` of the `ticks` run that frame gets: an even
+    /// share of the mouse movement, so thrust doesn't depend on the frame rate, and the key
+    /// presses on the first tick only.
+    pub fn split(&self, i: u32, ticks: u32) -> Input {
+        let [dx, dy] = self.mouse_relative;
+        let mouse_relative = [dx / ticks as f64, dy / ticks as f64];
+        if i == 0 {
+            Input{mouse_relative, .. *self}
+        } else {
+            Input{mouse_relative, .. Input::default()}
+        }
+    }
+}
+
+/// Something that happened to the game during a tick, for the front end to tell the player.
+#[derive(Debug)]
+pub enum GameEvent {
+    /// An object ran out of health. Everything but the player is despawned.
+    Destroyed {
+        victim: ObjectRole,
+        attacker: ObjectRole
+    },
+    /// The player ran out of health.
+    GameOver,
+    /// Wave `number`, counting from 1, of `total` spawned.
+    WaveSpawned {
+        number: usize,
+        total: usize
+    },
+    PickedUp {
+        class: String
+    },
+    /// A weapon from the loadout couldn't be spawned on the player.
+    EquipFailed {
+        class: String,
+        errors: Vec<LoadError>
+    },
+}
+
+impl fmt::Display for GameEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GameEvent::Destroyed{victim, attacker} => write!(f, "{:?} destroyed by {:?}", victim, attacker),
+            GameEvent::GameOver => write!(f, "game over"),
+            GameEvent::WaveSpawned{number, total} => write!(f, "wave {} of {}", number, total),
+            GameEvent::PickedUp{ref class} => write!(f, "picked up {}", class),
+            GameEvent::EquipFailed{ref class, ref errors} => {
+                write!(f, "could not equip {}", class)?;
+                for e in errors {
+                    write!(f, "\n{}", e)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+/// Turns elapsed wall-clock time into a whole number of fixed-length simulation ticks.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedTimestep {
+    pub tick_rate: f64,
+    /// Most ticks run for a single `advance`; time beyond that is dropped.
+    pub max_catch_up: u32,
+    accumulator: f64,
+}
+
+impl FixedTimestep {
+    pub fn new(tick_rate: f64, max_catch_up: u32) -> FixedTimestep {
+        FixedTimestep{tick_rate, max_catch_up, accumulator: 0.}
+    }
+
+    pub fn dt(&self) -> f64 {
+        1. / self.tick_rate
+    }
+
+    /// Adds `elapsed` seconds and returns how many ticks should be run now.
+    pub fn advance(&mut self, elapsed: f64) -> u32 {
+        let dt = self.dt();
+        self.accumulator += elapsed;
+        let mut ticks = 0;
+        while self.accumulator >= dt && ticks < self.max_catch_up {
+            self.accumulator -= dt;
+            ticks += 1;
+        }
+        if self.accumulator >= dt {
+            // too far behind to catch up, so slow down instead of spiralling
+            self.accumulator %= dt;
+        }
+        ticks
+    }
+
+    /// How far the current frame is between the last tick and the next one, from 0 to 1.
+    pub fn alpha(&self) -> f64 {
+        self.accumulator / self.dt()
+    }
+}
+
+/// How far a game has got through its level's waves.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct WaveProgress {
+    /// Index of the next wave to spawn.
+    pub next: usize,
+    /// Tick the last wave spawned on, or 0 before the first one.
+    pub last_spawned: u64,
+    /// Tick the next wave's trigger fired on, once it has.
+    pub triggered: Option<u64>,
+}
+
+/// The weapons the player carries, and the ones lying around the arena.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Loadout {
+    /// Weapon classes the player cycles through, in order.
+    pub classes: Vec<String>,
+    /// Index into `classes` of the weapon in hand, or of the one to equip next when empty handed.
+    pub current: usize,
+    /// The weapon in hand, if any.
+    pub equipped: Option<WeaponId>,
+    /// Weapons the player dropped, along with their classes.
+    pub dropped: Vec<(WeaponId, String)>,
+}
+
+impl Loadout {
+    pub fn new(classes: Vec<String>) -> Loadout {
+        Loadout{classes, .. Loadout::default()}
+    }
+}
+
+/// The rendering-free game state: the physics world and the objects living in it.
+pub struct Simulation {
+    pub world: b2::World<GameUserData>,
+    pub objects: SlotMap<GameObjectKey, GameObject>,
+    /// Behavior trees of the objects that move on their own.
+    pub brains: SecondaryMap<GameObjectKey, Brain>,
+    pub player: GameObjectKey,
+    pub cursor_captured: bool,
+    pub tick: u64,
+    pub tick_rate: f64,
+    /// Seeds everything random in the simulation, so the same seed and input replay the same game.
+    pub seed: u32,
+    pub textures: Vec<String>,
+    /// Set once the player runs out of health.
+    pub game_over: bool,
+    next_weapon: u32,
+    /// The files the level was built from, so snapshots can rebuild what's spawned later.
+    sources: Option<Sources>,
+    definitions: Option<Rc<Definitions>>,
+    pub waves: WaveProgress,
+    pub loadout: Loadout,
+    /// Used for every object spawned from now on.
+    pub collision: CollisionFilters,
+    pub damage: DamageDef,
+    /// Filled by the contact listener while the world steps.
+    pending_events: CollisionEvents,
+    events: Vec<CollisionEvent>,
+    game_events: Vec<GameEvent>,
+    previous: SecondaryMap<GameObjectKey, (b2::Vec2, f32)>,
+}
+
+impl Simulation {
+    /// An empty world; `load_level` adds the player and everything else.
+    pub fn new(tick_rate: f64, seed: u32) -> Simulation {
+        let gravity = b2::Vec2 { x: 0., y: -10. };
+        Simulation::with_world(b2::World::<GameUserData>::new(&gravity), tick_rate, seed)
+    }
+
+    /// Wraps an already populated world; the caller is responsible for the objects and player.
+    fn with_world(mut world: b2::World<GameUserData>, tick_rate: f64, seed: u32) -> Simulation {
+        let pending_events = Rc::new(RefCell::new(Vec::new()));
+        world.set_contact_listener(Box::new(FixRestitutionListener::new(pending_events.clone())));
+
+        Simulation {
+            world,
+            objects: SlotMap::with_key(),
+            brains: SecondaryMap::new(),
+            player: GameObjectKey::default(),
+            cursor_captured: true,
+            tick: 0,
+            tick_rate,
+            seed,
+            textures: Vec::new(),
+            game_over: false,
+            next_weapon: 0,
+            sources: None,
+            definitions: None,
+            waves: WaveProgress::default(),
+            loadout: Loadout::default(),
+            collision: CollisionFilters::default(),
+            damage: DamageDef::default(),
+            pending_events,
+            events: Vec::new(),
+            game_events: Vec::new(),
+            previous: SecondaryMap::new(),
+        }
+    }
+
+    /// Whether `snapshot` can save the world. wrapped2d can't take snapshots of pulley
+    /// joints yet.
+    pub fn can_snapshot(&self) -> bool {
+        !self.world.joints().any(|(_, joint)| matches!(**joint.borrow(), b2::UnknownJoint::Pulley(_)))
+    }
+
+    /// Panics if `can_snapshot` is false.
+    pub fn snapshot(&self) -> SimulationSnapshot {
+        SimulationSnapshot {
+            tick: self.tick,
+            tick_rate: self.tick_rate,
+            seed: self.seed,
+            cursor_captured: self.cursor_captured,
+            game_over: self.game_over,
+            next_weapon: self.next_weapon,
+            sources: self.sources.clone(),
+            waves: self.waves,
+            loadout: self.loadout.clone(),
+            player: self.player,
+            textures: self.textures.clone(),
+            objects: self.objects.clone(),
+            velocities: self.objects.iter()
+                .map(|(key, object)| {
+                    let body = self.world.body(object.physics_handle);
+                    (key, (*body.linear_velocity(), body.angular_velocity()))
+                })
+                .collect(),
+            brains: self.brains.iter().map(|(key, brain)| (key, brain.clone())).collect(),
+            world: WorldSnapshot::take(&self.world),
+        }
+    }
+
+    /// Rebuilds a simulation from a snapshot. Object keys are kept, body and joint handles
+    /// are not. Box2D's contact cache isn't part of the snapshot, so a restored game can
+    /// drift from one that kept running.
+    pub fn restore(snapshot: &SimulationSnapshot) -> Result<Simulation, Vec<LoadError>> {
+        let mut id_to_handle = IdToHandle::new();
+        let world = snapshot.world.rebuild(&mut id_to_handle);
+
+        let mut sim = Simulation::with_world(world, snapshot.tick_rate, snapshot.seed);
+        sim.tick = snapshot.tick;
+        sim.cursor_captured = snapshot.cursor_captured;
+        sim.game_over = snapshot.game_over;
+        sim.next_weapon = snapshot.next_weapon;
+        sim.waves = snapshot.waves;
+        sim.loadout = snapshot.loadout.clone();
+        if let Some(ref sources) = snapshot.sources {
+            sim.set_level(sources.clone(), sources.parse()?);
+        }
+        sim.player = snapshot.player;
+        sim.textures = snapshot.textures.clone();
+        sim.objects = snapshot.objects.clone();
+        sim.brains = snapshot.brains.iter().map(|(key, brain)| (key, brain.clone())).collect();
+        for (key, object) in sim.objects.iter_mut() {
+            object.physics_handle = id_to_handle.body_handle(BodyId(object.physics_handle.index()))
+                .expect("snapshot object refers to a body that isn't in the snapshot");
+            // adding fixtures back moved the centre of mass out from under the saved velocity
+            if let Some(&(linear, angular)) = snapshot.velocities.get(key) {
+                let mut body = sim.world.body_mut(object.physics_handle);
+                body.set_linear_velocity(&linear);
+                body.set_angular_velocity(angular);
+            }
+        }
+        Ok(sim)
+    }
+
+    pub fn step(&mut self, input: &Input) {
+        self.game_events.clear();
+        if input.toggle_capture {
+            self.cursor_captured = !self.cursor_captured;
+        }
+        if !self.game_over {
+            if input.cycle_weapon {
+                self.cycle_weapon();
+            }
+            if input.drop_weapon {
+                self.drop_weapon();
+            }
+            if input.pick_up_weapon {
+                self.pick_up_weapon();
+            }
+        }
+
+        for key in self.objects.keys() {
+            let handle = self.handle(key).unwrap();
+            self.obj_type(key).unwrap().update(self, input, handle);
+        }
+        self.think();
+
+        self.previous.clear();
+        for (key, object) in &self.objects {
+            let body = self.world.body(object.physics_handle);
+            self.previous.insert(key, (*body.position(), body.angle()));
+        }
+
+        self.world.step((1. / self.tick_rate) as f32, 20, 20);
+        self.events = self.pending_events.replace(Vec::new());
+        self.break_chains();
+        self.collect_pickups();
+        self.apply_damage();
+        self.update_waves();
+        self.tick += 1;
+    }
+
+    /// Snaps every breakable chain whose links were pulled harder than its break force. Whatever
+    /// the chain held up comes loose from its ship and turns into debris.
+    fn break_chains(&mut self) {
+        let dt = (1. / self.tick_rate) as f32;
+        let mut broken: Vec<(b2::JointHandle, GameObjectKey, GameObjectKey, b2::Vec2)> = Vec::new();
+        for (handle, joint) in self.world.joints() {
+            let joint = joint.borrow();
+            let link = [joint.body_a(), joint.body_b()].iter()
+                .filter_map(|&body| self.objects.get(self.world.body(body).user_data().key))
+                .filter(|object| object.weapon.is_some())
+                .filter_map(|object| match object.obj_type {
+                    GameObjectType::ChainLink{from, to, break_force: Some(break_force)} => Some((from, to, break_force)),
+                    _ => None,
+                })
+                .next();
+            if let Some((from, to, break_force)) = link {
+                let already = broken.iter().any(|&(_, other_from, other_to, _)| (other_from, other_to) == (from, to));
+                if !already && reaction_force(&joint, dt).norm() > break_force {
+                    broken.push((handle, from, to, joint.anchor_b()));
+                }
+            }
+        }
+
+        for (handle, from, to, point) in broken {
+            // the rope straight from one end to the other has to go as well
+            let end_handles = (self.handle(from), self.handle(to));
+            let tethers: Vec<b2::JointHandle> = self.world.joints()
+                .filter(|&(_, joint)| {
+                    let joint = joint.borrow();
+                    (Some(joint.body_a()), Some(joint.body_b())) == end_handles
+                        && joint.get_type() == b2::JointType::Rope
+                })
+                .map(|(tether, _)| tether)
+                .collect();
+            let owner = self.owner(from);
+            self.world.destroy_joint(handle);
+            for tether in tethers {
+                self.world.destroy_joint(tether);
+            }
+            self.release_debris(owner);
+            // the links still hanging off the owner would otherwise snap the same chain again
+            for object in self.objects.values_mut() {
+                if let GameObjectType::ChainLink{from: link_from, to: link_to, ref mut break_force} = object.obj_type {
+                    if (link_from, link_to) == (from, to) {
+                        *break_force = None;
+                    }
+                }
+            }
+            let ends = match (self.body(from), self.body(to)) {
+                (Some(from_body), Some(to_body)) => Some((*from_body.user_data(), *to_body.user_data())),
+                _ => None,
+            };
+            if let Some((from, to)) = ends {
+                self.events.push(CollisionEvent::ChainBroke{from, to, point});
+            }
+        }
+    }
+
+    /// Turns every part of `owner`'s weapons that's no longer joined to it into debris that
+    /// belongs to no one.
+    fn release_debris(&mut self, owner: GameObjectKey) {
+        let mut reached = match self.handle(owner) {
+            Some(handle) => vec![handle],
+            None => return,
+        };
+        let mut i = 0;
+        while i < reached.len() {
+            let others: Vec<b2::BodyHandle> = self.world.body(reached[i]).joints().map(|(other, _)| other).collect();
+            for other in others {
+                if !reached.contains(&other) {
+                    reached.push(other);
+                }
+            }
+            i += 1;
+        }
+
+        let loose: Vec<GameObjectKey> = self.objects.iter()
+            .filter(|&(key, object)| object.weapon.is_some() && self.owner(key) == owner && !reached.contains(&object.physics_handle))
+            .map(|(key, _)| key)
+            .collect();
+        let filter = self.collision.filter(CollisionLayer::Debris, None);
+        for key in loose {
+            self.objects[key].weapon = None;
+            self.set_filter(key, &filter);
+        }
+    }
+
+    /// Gives `filter` to every fixture of the object that collides with anything at all.
+    fn set_filter(&self, key: GameObjectKey, filter: &b2::Filter) {
+        let body = match self.body(key) {
+            Some(body) => body,
+            None => return,
+        };
+        let fixtures: Vec<b2::FixtureHandle> = body.fixtures().map(|(handle, _)| handle).collect();
+        for fixture in fixtures {
+            let mut fixture = body.fixture_mut(fixture);
+            if fixture.filter_data().category_bits != 0 {
+                fixture.set_filter_data(filter);
+            }
+        }
+    }
+
+    /// Runs one tick of every behavior tree.
+    fn think(&mut self) {
+        let mut brains = mem::replace(&mut self.brains, SecondaryMap::new());
+        for (key, brain) in brains.iter_mut() {
+            brain.think(self, key);
+        }
+        self.brains = brains;
+    }
+
+    /// Keeps the level the simulation was built from, for spawning waves later on.
+    pub fn set_level(&mut self, sources: Sources, definitions: Definitions) {
+        self.collision = definitions.level.collision.filters();
+        self.damage = definitions.level.damage;
+        self.sources = Some(sources);
+        self.definitions = Some(Rc::new(definitions));
+    }
+
+    /// Spawns the next wave once its trigger has fired and its delay has passed.
+    fn update_waves(&mut self) {
+        let definitions = match self.definitions {
+            Some(ref definitions) => definitions.clone(),
+            None => return,
+        };
+        let wave = match definitions.level.waves.get(self.waves.next) {
+            Some(wave) => wave,
+            None => return,
+        };
+
+        let (fired, delay) = match wave.trigger {
+            // timers count from the last wave, whatever happened since
+            WaveTrigger::Timer{delay} => (Some(self.waves.last_spawned), delay),
+            WaveTrigger::Cleared{delay} => {
+                let cleared = !self.objects.values()
+                    .any(|object| matches!(object.obj_type, GameObjectType::Chaser));
+                (if cleared { Some(self.tick) } else { None }, delay)
+            },
+            WaveTrigger::Area{x, y, radius} => {
+                let near = self.body(self.player)
+                    .is_some_and(|body| (*body.position() - b2::Vec2{x, y}).norm() <= radius);
+                (if near { Some(self.tick) } else { None }, 0.)
+            },
+        };
+        // once fired, a trigger stays fired until its wave spawns
+        if self.waves.triggered.is_none() {
+            self.waves.triggered = fired;
+        }
+        let triggered = match self.waves.triggered {
+            Some(tick) => tick,
+            None => return,
+        };
+
+        if self.tick >= triggered + (delay * self.tick_rate).round() as u64 {
+            self.game_events.push(GameEvent::WaveSpawned{number: self.waves.next + 1, total: definitions.level.waves.len()});
+            spawn_wave(self, &definitions, wave);
+            self.waves = WaveProgress{next: self.waves.next + 1, last_spawned: self.tick, triggered: None};
+        }
+    }
+
+    /// Damages both sides of every hard contact from the last step and removes dead chasers.
+    fn apply_damage(&mut self) {
+        let mut dead = Vec::new();
+        for event in &self.events {
+            let (a, b, impulse) = match *event {
+                CollisionEvent::Impact{a, b, impulse, ..} => (a, b, impulse),
+                _ => continue,
+            };
+            for &(victim, attacker) in &[(a, b), (b, a)] {
+                let multiplier = match self.objects.get(attacker.key) {
+                    Some(_) if !self.damage.own_weapons && self.owner(attacker.key) == victim.key => 0.,
+                    Some(object) => object.damage,
+                    // bodies that aren't objects are the walls and obstacles
+                    None => self.damage.scenery,
+                };
+                let object = match self.objects.get_mut(victim.key) {
+                    Some(object) => object,
+                    None => continue,
+                };
+                let health = match object.health {
+                    Some(ref mut health) if *health > 0. => health,
+                    _ => continue,
+                };
+                *health -= impulse * DAMAGE_PER_IMPULSE * multiplier;
+                if *health <= 0. {
+                    dead.push((victim, attacker));
+                }
+            }
+        }
+
+        for (victim, attacker) in dead {
+            let key = victim.key;
+            self.game_events.push(GameEvent::Destroyed{victim: victim.role, attacker: attacker.role});
+            match self.objects[key].obj_type {
+                GameObjectType::Player{..} => {
+                    self.game_events.push(GameEvent::GameOver);
+                    self.game_over = true;
+                },
+                _ => {
+                    self.despawn(key);
+                }
+            }
+        }
+    }
+
+    /// Adds an object and tags its body and fixtures with its key, so contacts can be traced
+    /// back to it.
+    pub fn insert_object(&mut self, object: GameObject, role: ObjectRole) -> GameObjectKey {
+        let key = self.objects.insert(object);
+        let data = ObjectData{key, role};
+        let owner = self.owner(key);
+        let layer = match role {
+            // pickups sense the player the way walls stop it
+            ObjectRole::Scenery | ObjectRole::Pickup => CollisionLayer::Wall,
+            ObjectRole::Player => CollisionLayer::Player,
+            ObjectRole::Chaser => CollisionLayer::Enemy,
+            ObjectRole::WeaponCollider | ObjectRole::ChainLink => match self.objects.get(owner).map(|o| o.obj_type) {
+                Some(GameObjectType::Player{..}) => CollisionLayer::PlayerWeapon,
+                _ => CollisionLayer::EnemyWeapon,
+            },
+        };
+        let filter = self.collision.filter(layer, Some(owner));
+        let mut body = self.world.body_mut(object.physics_handle);
+        *body.user_data_mut() = data;
+        let fixtures: Vec<b2::FixtureHandle> = body.fixtures().map(|(handle, _)| handle).collect();
+        for fixture in fixtures {
+            let mut fixture = body.fixture_mut(fixture);
+            *fixture.user_data_mut() = data;
+            // chain links that pass through everything keep their empty filter
+            if fixture.filter_data().category_bits != 0 {
+                fixture.set_filter_data(&filter);
+            }
+        }
+        key
+    }
+
+    /// The ship that `key` is part of a weapon of, following weapons that hang off other
+    /// weapons, or `key` itself if it isn't part of a weapon.
+    pub fn owner(&self, key: GameObjectKey) -> GameObjectKey {
+        let mut owner = key;
+        while let Some(part) = self.objects.get(owner).and_then(|object| object.weapon) {
+            owner = part.root;
+        }
+        owner
+    }
+
+
+    /// Position and angle of an object `alpha` of the way from the previous tick to the current one.
+    pub fn interpolated(&self, key: GameObjectKey, alpha: f64) -> Option<(b2::Vec2, f32)> {
+        let body = self.body(key)?;
+        let (position, angle) = (*body.position(), body.angle());
+        let (prev_position, prev_angle) = match self.previous.get(key) {
+            Some(p) => *p,
+            None => return Some((position, angle)),
+        };
+        let alpha = alpha as f32;
+        Some((prev_position + (position - prev_position) * alpha,
+              prev_angle + (angle - prev_angle) * alpha))
+    }
+
+    /// Steps `ticks` times, feeding the same input on every tick. Returns the game events of
+    /// all of those ticks in order, which leaves `game_events` empty.
+    pub fn run(&mut self, ticks: u64, input: &Input) -> Vec<GameEvent> {
+        let mut events = Vec::new();
+        for _ in 0 .. ticks {
+            self.step(input);
+            events.append(&mut self.game_events);
+        }
+        events
+    }
+
+    /// Removes an object and, recursively, every weapon attached to it, destroying their
+    /// bodies and joints. Returns the keys that were removed. The player can't be despawned.
+    /// This is the only way objects are removed; `Game` drops the sprites of missing objects
+    /// the next time it draws, so front ends don't need to be told.
+    pub fn despawn(&mut self, key: GameObjectKey) -> Vec<GameObjectKey> {
+        if key == self.player {
+            return Vec::new();
+        }
+        if !self.objects.contains_key(key) {
+            return Vec::new();
+        }
+        let mut removed = Vec::new();
+        let mut to_visit = vec![key];
+        while let Some(key) = to_visit.pop() {
+            removed.push(key);
+            to_visit.extend(self.objects.iter()
+                .filter(|&(_, object)| object.weapon.is_some_and(|part| part.root == key))
+                .map(|(key, _)| key));
+        }
+        for &key in &removed {
+            let object = self.objects.remove(key).unwrap();
+            // destroying a body also destroys every joint attached to it
+            self.world.destroy_body(object.physics_handle);
+            self.previous.remove(key);
+            self.brains.remove(key);
+        }
+        removed
+    }
+
+    /// Despawns every collider and chain link of a weapon. Returns the keys that were removed.
+    pub fn despawn_weapon(&mut self, weapon: WeaponId) -> Vec<GameObjectKey> {
+        let parts: Vec<GameObjectKey> = self.objects.iter()
+            .filter(|&(_, object)| object.weapon.is_some_and(|part| part.weapon == weapon))
+            .map(|(key, _)| key)
+            .collect();
+        let mut removed = Vec::new();
+        for key in parts {
+            removed.extend(self.despawn(key));
+        }
+        removed
+    }
+
+    /// Puts away the weapon in hand and equips the next one in the loadout, or equips the
+    /// current one when empty handed.
+    pub fn cycle_weapon(&mut self) {
+        if self.loadout.classes.is_empty() {
+            return;
+        }
+        if let Some(weapon) = self.loadout.equipped.take() {
+            self.despawn_weapon(weapon);
+            self.loadout.current = (self.loadout.current + 1) % self.loadout.classes.len();
+        }
+        let class = self.loadout.classes[self.loadout.current].clone();
+        self.equip(&class);
+    }
+
+    /// Lets go of the weapon in hand, cutting it loose from the player. It lies in the arena as
+    /// debris and is out of the loadout until it's picked up again.
+    pub fn drop_weapon(&mut self) {
+        let weapon = match self.loadout.equipped.take() {
+            Some(weapon) => weapon,
+            None => return,
+        };
+        let handle = match self.handle(self.player) {
+            Some(handle) => handle,
+            None => return,
+        };
+        let joints: Vec<b2::JointHandle> = self.world.body(handle).joints()
+            .filter(|&(other, _)| {
+                let key = self.world.body(other).user_data().key;
+                self.objects.get(key).and_then(|object| object.weapon).is_some_and(|part| part.weapon == weapon)
+            })
+            .map(|(_, joint)| joint)
+            .collect();
+        for joint in joints {
+            self.world.destroy_joint(joint);
+        }
+
+        for object in self.objects.values_mut() {
+            if let Some(ref mut part) = object.weapon {
+                if part.weapon == weapon {
+                    part.root = GameObjectKey::default();
+                }
+            }
+        }
+        // the weapon and anything hanging off it now belong to no one
+        let filter = self.collision.filter(CollisionLayer::Debris, None);
+        let loose: Vec<GameObjectKey> = self.objects.iter()
+            .filter(|&(key, object)| object.weapon.is_some() && self.owner(key) == GameObjectKey::default())
+            .map(|(key, _)| key)
+            .collect();
+        for key in loose {
+            self.set_filter(key, &filter);
+        }
+
+        let class = self.loadout.classes.remove(self.loadout.current);
+        if self.loadout.current >= self.loadout.classes.len() {
+            self.loadout.current = 0;
+        }
+        self.loadout.dropped.push((weapon, class));
+    }
+
+    /// Swaps the weapon in hand for the closest dropped weapon within `PICK_UP_DISTANCE`, which
+    /// goes into the loadout where the old one was.
+    pub fn pick_up_weapon(&mut self) {
+        let position = match self.body(self.player) {
+            Some(body) => *body.position(),
+            None => return,
+        };
+        let closest = self.objects.values()
+            .filter_map(|object| {
+                let part = object.weapon?;
+                let i = self.loadout.dropped.iter().position(|&(weapon, _)| weapon == part.weapon)?;
+                let distance = (*self.world.body(object.physics_handle).position() - position).norm();
+                Some((i, distance))
+            })
+            .filter(|&(_, distance)| distance < PICK_UP_DISTANCE)
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        let (weapon, class) = match closest {
+            Some((i, _)) => self.loadout.dropped.remove(i),
+            None => return,
+        };
+
+        self.drop_weapon();
+        self.despawn_weapon(weapon);
+        self.loadout.current = match self.loadout.classes.iter().position(|other| *other == class) {
+            Some(i) => i,
+            None => {
+                self.loadout.classes.insert(self.loadout.current, class.clone());
+                self.loadout.current
+            }
+        };
+        self.equip(&class);
+    }
+
+    /// Hands the player the weapon of every pickup it touched, and removes the pickups.
+    fn collect_pickups(&mut self) {
+        let definitions = match self.definitions {
+            Some(ref definitions) if !self.game_over => definitions.clone(),
+            _ => return,
+        };
+        let touched: Vec<(GameObjectKey, usize)> = self.events.iter()
+            .filter_map(|event| match *event {
+                CollisionEvent::ContactBegan{a, b} if a.key == self.player => Some(b.key),
+                CollisionEvent::ContactBegan{a, b} if b.key == self.player => Some(a.key),
+                _ => None,
+            })
+            .filter_map(|key| match self.objects.get(key)?.obj_type {
+                GameObjectType::Pickup{index} => Some((key, index)),
+                _ => None,
+            })
+            .collect();
+        for (key, index) in touched {
+            // a pickup touched more than once in a tick is only collected once
+            if self.despawn(key).is_empty() {
+                continue;
+            }
+            let class = definitions.level.pickups[index].class.value.clone();
+            self.game_events.push(GameEvent::PickedUp{class: class.clone()});
+            self.give_weapon(class);
+        }
+    }
+
+    /// Puts a weapon of `class` in hand in place of the current one, adding it to the loadout
+    /// unless it's there already.
+    pub fn give_weapon(&mut self, class: String) {
+        if let Some(weapon) = self.loadout.equipped.take() {
+            self.despawn_weapon(weapon);
+        }
+        self.loadout.current = match self.loadout.classes.iter().position(|other| *other == class) {
+            Some(i) => i,
+            None => {
+                self.loadout.classes.push(class.clone());
+                self.loadout.classes.len() - 1
+            }
+        };
+        self.equip(&class);
+    }
+
+    /// Spawns a weapon of `class` on the player and puts it in hand.
+    fn equip(&mut self, class: &str) {
+        let definitions = match self.definitions {
+            Some(ref definitions) => definitions.clone(),
+            None => return,
+        };
+        let player = self.player;
+        match load_weapon(self, &definitions.weapons[class], &definitions.props, player) {
+            Ok((weapon, _)) => self.loadout.equipped = Some(weapon),
+            Err(errors) => self.game_events.push(GameEvent::EquipFailed{class: String::from(class), errors}),
+        }
+    }
+
+    pub fn new_weapon_id(&mut self) -> WeaponId {
+        self.next_weapon += 1;
+        WeaponId(self.next_weapon - 1)
+    }
+
+    /// Everything that collided during the last tick. Replaced on every step, so each system
+    /// reading it sees every event exactly once if it reads once per tick.
+    pub fn events(&self) -> &[CollisionEvent] {
+        &self.events
+    }
+
+    /// What happened to the game during the last tick. Replaced on every step like `events`.
+    pub fn game_events(&self) -> &[GameEvent] {
+        &self.game_events
+    }
+
+    /// Hash of every body's exact position, angle and velocity; equal hashes mean the runs
+    /// haven't diverged.
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = Fnv1a::new();
+        hasher.write_u64(self.tick);
+        for (_, object) in &self.objects {
+            let body = self.world.body(object.physics_handle);
+            for value in &[body.position().x, body.position().y, body.angle(),
+                           body.linear_velocity().x, body.linear_velocity().y, body.angular_velocity()] {
+                hasher.write_u32(value.to_bits());
+            }
+            if let Some(health) = object.health {
+                hasher.write_u32(health.to_bits());
+            }
+        }
+        hasher.finish()
+    }
+
+    /// Looks up a texture by file name, registering it if it hasn't been seen yet.
+    pub fn texture_id(&mut self, name: &str) -> TextureId {
+        match self.textures.iter().position(|t| t == name) {
+            Some(i) => TextureId(i),
+            None => {
+                self.textures.push(String::from(name));
+                TextureId(self.textures.len() - 1)
+            }
+        }
+    }
+
+    pub fn body(&self, key: GameObjectKey) -> Option<Ref<b2::MetaBody<GameUserData>>> {
+        Some(self.world.body(self.objects.get(key)?.physics_handle))
+    }
+
+    pub fn handle(&self, key: GameObjectKey) -> Option<TypedHandle<b2::Body>> {
+        Some(self.objects.get(key)?.physics_handle)
+    }
+
+    pub fn obj_type(&self, key: GameObjectKey) -> Option<GameObjectType> {
+        Some(self.objects.get(key)?.obj_type)
+    }
+}
+
+/// 64-bit FNV-1a. Unlike `DefaultHasher` its output is fixed, so a state hash saved in a replay
+/// still means the same thing after a compiler upgrade.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Fnv1a {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    // The default integer writes use native byte order; pin it so hashes match across machines.
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use swingyships::game::GameObjectType;
+    use swingyships::physics::{CollisionEvent, ObjectData, ObjectRole};
+    use swingyships::testing::{build_level, build_level_from, read_level};
+    use super::{Fnv1a, GameEvent, Input, Simulation};
+
+    use wrapped2d::b2;
+
+    use std::hash::Hasher;
+
+    fn swirl(tick: u64) -> Input {
+        Input {
+            mouse_relative: [(tick as f64 * 0.05).cos() * 20., (tick as f64 * 0.03).sin() * 20.],
+            ..Input::default()
+        }
+    }
+
+    #[test]
+    fn run_is_the_same_as_stepping_each_tick() {
+        let mut run = build_level("level.toml");
+        let mut stepped = build_level("level.toml");
+        let input = swirl(3);
+        run.run(300, &input);
+        for _ in 0 .. 300 {
+            stepped.step(&input);
+        }
+        assert_eq!(run.tick, 300);
+        assert_eq!(stepped.tick, 300);
+        assert_eq!(run.state_hash(), stepped.state_hash());
+    }
+
+    #[test]
+    fn same_level_and_input_give_the_same_state() {
+        fn play<F: Fn(u64) -> Input>(input: F) -> u64 {
+            let mut sim = build_level("level_all_weapons.toml");
+            while sim.tick < 600 {
+                let input = input(sim.tick);
+                sim.step(&input);
+            }
+            sim.state_hash()
+        }
+        assert_eq!(play(swirl), play(swirl));
+        // and the input does make a difference
+        assert_ne!(play(swirl), play(|_| Input::default()));
+    }
+
+    #[test]
+    fn mouse_motion_moves_the_player_the_same_at_any_frame_rate() {
+        // the mouse moves at the same speed, drawn once per tick or once every two ticks
+        let mut one_per_frame = build_level("level.toml");
+        let mut two_per_frame = build_level("level.toml");
+        let per_tick = Input{mouse_relative: [0.5, -0.25], .. Input::default()};
+        let per_two_ticks = Input{mouse_relative: [1., -0.5], .. Input::default()};
+        while one_per_frame.tick < 60 {
+            one_per_frame.step(&per_tick.split(0, 1));
+        }
+        while two_per_frame.tick < 60 {
+            two_per_frame.step(&per_two_ticks.split(0, 2));
+            two_per_frame.step(&per_two_ticks.split(1, 2));
+        }
+        let velocity = |sim: &Simulation| *sim.body(sim.player).unwrap().linear_velocity();
+        assert!(velocity(&one_per_frame).norm() > 1.);
+        assert_eq!(velocity(&one_per_frame), velocity(&two_per_frame));
+    }
+
+    /// A level with just the player holding `loadout`, with `extra` added to the level file.
+    fn lone_player(loadout: &str, extra: &str) -> Simulation {
+        build_level_from(&format!("{}
+[player]
+  spawn = [50.0, -50.0]
+  loadout = [{}]
+
+[chasers.props]
+  linear_damping = 1.0
+  scale = 1.0
+  density = 1.0
+  restitution = 0.5
+", extra, loadout))
+    }
+
+    #[test]
+    fn walls_only_hurt_when_the_level_says_so() {
+        fn ram_wall(extra: &str) -> f32 {
+            let mut sim = lone_player("", extra);
+            let player = sim.handle(sim.player).unwrap();
+            sim.world.body_mut(player).set_linear_velocity(&b2::Vec2{x: 300., y: 0.});
+            sim.run(120, &Input::default());
+            sim.objects[sim.player].health.unwrap()
+        }
+        assert_eq!(ram_wall(""), 100.);
+        assert!(ram_wall("[damage]\n  scenery = 1.0\n") < 100.);
+    }
+
+    #[test]
+    fn own_weapons_only_hurt_when_the_level_says_so() {
+        // the whip rarely swings back into its ship, so hit the player with it directly
+        fn whipped(extra: &str) -> f32 {
+            let mut sim = lone_player("\"whip\"", extra);
+            let whip = sim.objects.iter()
+                .find(|&(_, object)| object.weapon.is_some() && matches!(object.obj_type, GameObjectType::Default))
+                .map(|(key, _)| key)
+                .unwrap();
+            let zero = b2::Vec2{x: 0., y: 0.};
+            sim.events = vec![CollisionEvent::Impact {
+                a: ObjectData{key: sim.player, role: ObjectRole::Player},
+                b: ObjectData{key: whip, role: ObjectRole::WeaponCollider},
+                point: zero,
+                normal: b2::Vec2{x: 1., y: 0.},
+                impulse: 1000.,
+                relative_velocity: zero
+            }];
+            sim.apply_damage();
+            sim.objects[sim.player].health.unwrap()
+        }
+        assert_eq!(whipped(""), 100.);
+        assert!(whipped("[damage]\n  own_weapons = true\n") < 100.);
+    }
+
+    #[test]
+    fn waves_spawn_once_their_timer_runs_out() {
+        let mut sim = lone_player("", "
+[spawn_points]
+  east = [80.0, -50.0]
+
+[[waves]]
+  trigger = { kind = \"timer\", delay = 1.0 }
+[[waves.spawns]]
+  at = \"east\"
+  count = 2
+");
+        let chasers = |sim: &Simulation| sim.objects.values()
+            .filter(|object| matches!(object.obj_type, GameObjectType::Chaser))
+            .count();
+        assert!(sim.run(59, &Input::default()).is_empty());
+        assert_eq!(chasers(&sim), 0);
+        let events = sim.run(2, &Input::default());
+        assert!(matches!(events[..], [GameEvent::WaveSpawned{number: 1, total: 1}]), "{:?}", events);
+        assert_eq!(chasers(&sim), 2);
+    }
+
+    #[test]
+    fn despawning_a_chaser_removes_its_weapon() {
+        let mut sim = build_level("level.toml");
+        let armed = sim.objects.iter()
+            .find(|&(key, object)| matches!(object.obj_type, GameObjectType::Chaser)
+                && sim.objects.values().any(|object| object.weapon.is_some_and(|part| part.root == key)))
+            .map(|(key, _)| key)
+            .unwrap();
+        let bodies = sim.world.bodies().count();
+        let removed = sim.despawn(armed);
+        assert!(removed.len() > 1);
+        assert!(removed.iter().all(|&key| !sim.objects.contains_key(key)));
+        assert_eq!(sim.world.bodies().count(), bodies - removed.len());
+        assert!(sim.despawn(sim.player).is_empty());
+    }
+
+    #[test]
+    fn dropped_weapons_can_be_picked_up_again() {
+        let mut sim = lone_player("\"whip\", \"mace\"", "");
+        let in_hand = |sim: &Simulation| sim.loadout.classes[sim.loadout.current].clone();
+        assert_eq!(in_hand(&sim), "whip");
+        sim.cycle_weapon();
+        assert_eq!(in_hand(&sim), "mace");
+        let mace = sim.loadout.equipped.unwrap();
+
+        sim.drop_weapon();
+        assert_eq!(sim.loadout.equipped, None);
+        assert_eq!(sim.loadout.classes, ["whip"]);
+        assert!(sim.objects.values().any(|object| object.weapon.is_some_and(|part| part.weapon == mace)));
+
+        sim.run(10, &Input::default());
+        sim.pick_up_weapon();
+        assert!(sim.loadout.dropped.is_empty());
+        assert_eq!(sim.loadout.classes, ["mace", "whip"]);
+        assert_eq!(in_hand(&sim), "mace");
+        assert!(sim.loadout.equipped.is_some());
+        // the dropped mace is gone and a new one is in hand
+        assert!(!sim.objects.values().any(|object| object.weapon.is_some_and(|part| part.weapon == mace)));
+    }
+
+    #[test]
+    fn touching_a_pickup_puts_its_weapon_in_hand() {
+        let mut sim = lone_player("\"whip\"", "
+[[pickups]]
+  class = \"mace\"
+  x = 60.0
+  y = -50.0
+");
+        let player = sim.handle(sim.player).unwrap();
+        sim.world.body_mut(player).set_linear_velocity(&b2::Vec2{x: 20., y: 0.});
+        let events = sim.run(60, &Input::default());
+        assert!(matches!(events[..], [GameEvent::PickedUp{ref class}] if class == "mace"), "{:?}", events);
+        assert_eq!(sim.loadout.classes, ["whip", "mace"]);
+        assert_eq!(sim.loadout.current, 1);
+        assert!(!sim.objects.values().any(|object| matches!(object.obj_type, GameObjectType::Pickup{..})));
+    }
+
+    #[test]
+    fn fnv1a_matches_reference_values() {
+        let hash = |bytes: &[u8]| {
+            let mut hasher = Fnv1a::new();
+            hasher.write(bytes);
+            hasher.finish()
+        };
+        assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(hash(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn chain_breaks_at_most_once() {
+        let mut sources = read_level("level_joints.toml");
+        // weak enough that the links left on the chaser after the first break get pulled past it too
+        let mut weakened = 0;
+        for weapon in &mut sources.weapons {
+            let contents = weapon.contents.replace("break_force = 4.0", "break_force = 2.0");
+            if contents != weapon.contents {
+                weapon.contents = contents;
+                weakened += 1;
+            }
+        }
+        assert_eq!(weakened, 1, "expected only breakaway.toml to have `break_force = 4.0`");
+        let mut sim = sources.build(60., 0).expect("could not build the level");
+        let mut breaks = 0;
+        while sim.tick < 300 {
+            sim.step(&Input::default());
+            breaks += sim.events().iter().filter(|event| matches!(event, CollisionEvent::ChainBroke{..})).count();
+        }
+        assert_eq!(breaks, 1);
+    }
+}
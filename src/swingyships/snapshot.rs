@@ -0,0 +1,144 @@
+extern crate wrapped2d;
+extern crate slotmap;
+extern crate serde;
+extern crate serde_json;
+
+use swingyships::game::{GameObject, GameObjectKey, SpriteObjectType};
+use swingyships::physics::GameUserData;
+use swingyships::level_loader::Sources;
+use swingyships::load_error::{LoadError, Location};
+use swingyships::simulation::{Loadout, WaveProgress};
+use swingyships::ai::Brain;
+
+use wrapped2d::b2;
+use wrapped2d::serialize::WorldSnapshot;
+
+use slotmap::{SlotMap, SecondaryMap};
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Bumped whenever the snapshot layout changes; older files are refused rather than misread.
+pub const SNAPSHOT_VERSION: u32 = 12;
+
+/// A whole game written to disk: the simulation plus the effects on screen.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub sim: SimulationSnapshot,
+    #[serde(default)]
+    pub sprites: Vec<SpriteSnapshot>
+}
+
+/// Everything needed to rebuild a `Simulation`: every body, fixture and joint in the
+/// world and the objects that own them. Object physics handles are stored as body ids.
+#[derive(Serialize, Deserialize)]
+pub struct SimulationSnapshot {
+    pub tick: u64,
+    pub tick_rate: f64,
+    pub seed: u32,
+    pub cursor_captured: bool,
+    pub game_over: bool,
+    pub next_weapon: u32,
+    /// The level files, parsed again on restore so waves can keep spawning.
+    pub sources: Option<Sources>,
+    pub waves: WaveProgress,
+    pub loadout: Loadout,
+    pub player: GameObjectKey,
+    pub textures: Vec<String>,
+    pub objects: SlotMap<GameObjectKey, GameObject>,
+    /// Linear and angular velocity of every object's body. The world snapshot has them too,
+    /// but Box2D shifts them while it puts the bodies' fixtures back.
+    pub velocities: SecondaryMap<GameObjectKey, (b2::Vec2, f32)>,
+    pub brains: SecondaryMap<GameObjectKey, Brain>,
+    pub world: WorldSnapshot<GameUserData>
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct SpriteSnapshot {
+    pub kind: SpriteObjectType,
+    pub position: [f64; 2]
+}
+
+#[derive(Deserialize)]
+struct VersionOnly {
+    version: u32
+}
+
+impl Snapshot {
+    pub fn new(sim: SimulationSnapshot, sprites: Vec<SpriteSnapshot>) -> Snapshot {
+        Snapshot{version: SNAPSHOT_VERSION, sim, sprites}
+    }
+
+    /// Reads a snapshot written by `save`, refusing ones from another `SNAPSHOT_VERSION`.
+    pub fn load(path: &Path) -> Result<Snapshot, Vec<LoadError>> {
+        let path_string = path.to_string_lossy().into_owned();
+        let contents = fs::read_to_string(path)
+            .map_err(|error| vec![LoadError::Io{path: path_string.clone(), error}])?;
+        let parse_error = |e: serde_json::Error| vec![LoadError::Parse {
+            at: Location{path: path_string.clone(), position: Some((e.line(), e.column()))},
+            message: e.to_string()
+        }];
+        let VersionOnly{version} = serde_json::from_str(&contents).map_err(&parse_error)?;
+        if version != SNAPSHOT_VERSION {
+            return Err(vec![LoadError::Parse {
+                at: Location{path: path_string.clone(), position: None},
+                message: format!("snapshot is version {}, expected version {}", version, SNAPSHOT_VERSION)
+            }]);
+        }
+        serde_json::from_str(&contents).map_err(&parse_error)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let contents = serde_json::to_string(self).expect("could not serialize snapshot");
+        fs::write(path, contents)
+    }
+}
+
+/// Serializes a body handle as the `BodyId` the world snapshot uses for it. The handle read
+/// back is only a placeholder until `Simulation::restore` maps it to the rebuilt body.
+pub mod body_id {
+    use wrapped2d::b2;
+    use wrapped2d::handle::TypedHandle;
+    use wrapped2d::serialize::BodyId;
+    use super::serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+    pub fn serialize<S: Serializer>(handle: &TypedHandle<b2::Body>, serializer: S) -> Result<S::Ok, S::Error> {
+        BodyId(handle.index()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<TypedHandle<b2::Body>, D::Error> {
+        let BodyId(index) = BodyId::deserialize(deserializer)?;
+        Ok(TypedHandle::new(index, 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use swingyships::simulation::{Input, Simulation};
+    use swingyships::testing::{build_level, temp_file};
+    use super::Snapshot;
+
+    use std::fs;
+
+    #[test]
+    fn restored_snapshot_has_the_saved_state() {
+        let mut sim = build_level("level_waves.toml");
+        // the axe's blade is off its body's centre, which used to shift its velocity on restore
+        while sim.loadout.classes[sim.loadout.current] != "axe" {
+            sim.cycle_weapon();
+        }
+        sim.run(400, &Input::default());
+
+        let path = temp_file("snapshot.json");
+        Snapshot::new(sim.snapshot(), Vec::new()).save(&path).expect("could not save the snapshot");
+        let loaded = Snapshot::load(&path);
+        fs::remove_file(&path).expect("could not remove the snapshot");
+        let restored = Simulation::restore(&loaded.expect("could not load the snapshot").sim)
+            .expect("could not restore the snapshot");
+
+        assert_eq!(restored.tick, sim.tick);
+        assert_eq!(restored.state_hash(), sim.state_hash());
+    }
+}
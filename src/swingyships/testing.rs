@@ -0,0 +1,36 @@
+// Fixtures shared by the unit tests.
+
+use swingyships::level_loader::{Sources, SourceFile};
+use swingyships::simulation::Simulation;
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
+
+pub fn assets() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("assets")
+}
+
+/// Reads a level from the assets folder along with the stock collider props and weapons.
+pub fn read_level(level: &str) -> Sources {
+    let assets = assets();
+    Sources::read(&assets.join(level), &assets.join("weapon_classes.toml"), &assets.join("weapons"))
+        .expect("could not read the level")
+}
+
+/// Builds a level from the assets folder at 60 ticks per second with seed 0.
+pub fn build_level(level: &str) -> Simulation {
+    read_level(level).build(60., 0).expect("could not build the level")
+}
+
+/// Builds a level written out in the test, with the stock collider props and weapons.
+pub fn build_level_from(contents: &str) -> Simulation {
+    let mut sources = read_level("level.toml");
+    sources.level = SourceFile{path: String::from("test_level.toml"), contents: String::from(contents)};
+    sources.build(60., 0).expect("could not build the level")
+}
+
+/// A file in the temp folder that tests running at the same time won't also use.
+pub fn temp_file(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("swingyships_{}_{}", process::id(), name))
+}